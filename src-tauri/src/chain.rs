@@ -0,0 +1,74 @@
+use crate::engine::{connect_proxy, negotiate, secure_transport, Tunnel};
+use crate::error::UpstreamError;
+use crate::state::{Proxy, ProxyPool};
+
+// Proxy chains (`chain`): an ordered list of pool proxies used as one
+// upstream. The first hop is dialled directly, every later hop is reached
+// through the tunnel opened by the one before it, and the last hop opens the
+// tunnel to the target.
+
+/// Looks up a chain's hops in the pool, in order.
+pub(crate) fn resolve_hops(pool: &ProxyPool, hop_ids: &[String]) -> Result<Vec<Proxy>, String> {
+    if hop_ids.is_empty() {
+        return Err("A chain needs at least one hop".to_string());
+    }
+    let mut hops = Vec::with_capacity(hop_ids.len());
+    for (i, id) in hop_ids.iter().enumerate() {
+        let hop = pool
+            .proxies
+            .get(id)
+            .map(|r| r.value().clone())
+            .ok_or_else(|| format!("Proxy with id {} not found", id))?;
+        if hop.is_chain() {
+            return Err("Chains cannot contain other chains".to_string());
+        }
+        // SSH sessions are dialled directly, so SSH can only be the entry.
+        if hop.is_ssh() && i > 0 {
+            return Err("An SSH proxy can only be the first hop of a chain".to_string());
+        }
+        hops.push(hop);
+    }
+    Ok(hops)
+}
+
+/// The hop traffic leaves the chain from, if it still resolves.
+pub(crate) fn exit_hop(pool: &ProxyPool, chain: &Proxy) -> Option<Proxy> {
+    let id = chain.hops.last()?;
+    pool.proxies.get(id).map(|r| r.value().clone())
+}
+
+/// Opens a tunnel to `target_host:target_port` through every hop of `chain`.
+pub(crate) async fn open(
+    pool: &ProxyPool,
+    chain: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    let hops = resolve_hops(pool, &chain.hops).map_err(UpstreamError::Protocol)?;
+
+    // Where each hop is asked to connect: the next hop, then the target.
+    let next = |i: usize| match hops.get(i + 1) {
+        Some(hop) => (hop.host.as_str(), hop.port),
+        None => (target_host, target_port),
+    };
+
+    let entry = &hops[0];
+    let mut tunnel = if entry.is_ssh() {
+        let (host, port) = next(0);
+        Tunnel {
+            stream: crate::ssh::connect(entry, host, port).await?,
+            bound: None,
+        }
+    } else {
+        let st = connect_proxy(entry).await?;
+        let (host, port) = next(0);
+        negotiate(st, entry, host, port).await?
+    };
+
+    for (i, hop) in hops.iter().enumerate().skip(1) {
+        let st = secure_transport(tunnel.stream, hop).await?;
+        let (host, port) = next(i);
+        tunnel = negotiate(st, hop, host, port).await?;
+    }
+    Ok(tunnel)
+}
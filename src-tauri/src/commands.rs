@@ -1,9 +1,41 @@
-use crate::engine::ProxyServer;
-use crate::state::{Proxy, ProxyPool, RotationMode};
+use crate::engine::{ProxyServer, DEFAULT_LISTENER_ID};
+use crate::geoip::{DatabaseStatus, GeoIp};
+use crate::groups::{GroupConfig, GroupInfo, Groups};
+use crate::port_map::{PortMap, PortMapSettings, PortMapping};
+use crate::rules::{Condition, Rule, RuleAction, Rules};
+use crate::state::{
+    AuthUser, Fallback, InboundProtocol, ListenerConfig, ListenerInfo, Proxy, ProxyPool,
+    RotationMode, DEFAULT_FAILOVER_RETRIES,
+};
+use crate::warm::{WarmPool, WarmPoolSettings};
+use std::collections::{BTreeMap, HashSet};
 use tauri::State;
 use uuid::Uuid;
 
+/// Rejects chains, which have their own commands, and Shadowsocks proxies
+/// whose cipher (or `user` fallback) is unknown.
+fn validate_protocol(
+    protocol: &str,
+    cipher: Option<&str>,
+    user: Option<&str>,
+) -> Result<(), String> {
+    if protocol == "chain" {
+        return Err("Chains are managed with add_chain/update_chain".to_string());
+    }
+    if protocol != "ss" {
+        return Ok(());
+    }
+    let name = cipher
+        .or(user)
+        .ok_or_else(|| "Shadowsocks proxies need a cipher".to_string())?;
+    if crate::ss::parse_cipher(name).is_none() {
+        return Err(format!("Unknown Shadowsocks cipher: {}", name));
+    }
+    Ok(())
+}
+
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub fn add_proxy(
     pool: State<ProxyPool>,
     protocol: Option<String>,
@@ -11,22 +43,100 @@ pub fn add_proxy(
     port: u16,
     user: Option<String>,
     pass: Option<String>,
+    skip_tls_verify: Option<bool>,
+    cipher: Option<String>,
+    private_key: Option<String>,
+    host_key: Option<String>,
+    headers: Option<BTreeMap<String, String>>,
+    tags: Option<Vec<String>>,
 ) -> Result<String, String> {
+    let protocol = protocol.unwrap_or_else(|| "socks5".to_string());
+    validate_protocol(&protocol, cipher.as_deref(), user.as_deref())?;
+    let headers = headers.unwrap_or_default();
+    crate::http_connect::validate_headers(&headers)?;
     let id = Uuid::new_v4().to_string();
     let p = Proxy {
         id: id.clone(),
-        protocol: protocol.unwrap_or_else(|| "socks5".to_string()),
+        protocol,
         host,
         port,
         user,
         pass,
         latency_ms: None,
         is_alive: false,
+        skip_tls_verify: skip_tls_verify.unwrap_or(false),
+        cipher,
+        private_key,
+        host_key,
+        hops: Vec::new(),
+        headers,
+        tags: normalize_tags(tags.unwrap_or_default()),
+        exit_ip: None,
+        host_ip: None,
+        geo: None,
     };
     pool.add(p);
     Ok(id)
 }
 
+#[tauri::command]
+pub fn add_chain(
+    pool: State<ProxyPool>,
+    name: String,
+    hops: Vec<String>,
+) -> Result<String, String> {
+    crate::chain::resolve_hops(&pool, &hops)?;
+    let id = Uuid::new_v4().to_string();
+    pool.add(Proxy {
+        id: id.clone(),
+        protocol: "chain".to_string(),
+        host: name,
+        port: 0,
+        user: None,
+        pass: None,
+        latency_ms: None,
+        is_alive: false,
+        skip_tls_verify: false,
+        cipher: None,
+        private_key: None,
+        host_key: None,
+        hops,
+        headers: BTreeMap::new(),
+        tags: Vec::new(),
+        exit_ip: None,
+        host_ip: None,
+        geo: None,
+    });
+    Ok(id)
+}
+
+#[tauri::command]
+pub fn update_chain(
+    pool: State<ProxyPool>,
+    id: String,
+    name: String,
+    hops: Vec<String>,
+) -> Result<(), String> {
+    if hops.contains(&id) {
+        return Err("A chain cannot contain itself".to_string());
+    }
+    crate::chain::resolve_hops(&pool, &hops)?;
+    match pool.proxies.get_mut(&id) {
+        Some(mut p) if p.is_chain() => {
+            p.host = name;
+            p.hops = hops;
+            p.is_alive = false;
+            p.latency_ms = None;
+            p.exit_ip = None;
+            p.host_ip = None;
+            p.geo = None;
+            Ok(())
+        }
+        Some(_) => Err(format!("Proxy with id {} is not a chain", id)),
+        None => Err(format!("Proxy with id {} not found", id)),
+    }
+}
+
 #[tauri::command]
 pub fn get_proxy_list(pool: State<ProxyPool>) -> Result<Vec<Proxy>, String> {
     Ok(pool.get_all())
@@ -48,10 +158,19 @@ pub fn get_listen_port(server: State<ProxyServer>) -> Result<u16, String> {
 }
 
 #[tauri::command]
-pub fn set_listen_port(server: State<ProxyServer>, port: u16) -> Result<(), String> {
-    if port < 1024 {
-        return Err("Port must be >= 1024".to_string());
-    }
+pub fn set_listen_port(
+    server: State<ProxyServer>,
+    port_map: State<PortMap>,
+    port: u16,
+) -> Result<(), String> {
+    validate_listen_port(port)?;
+    check_port_free(
+        &server,
+        &port_map,
+        &server.get_host(),
+        port,
+        DEFAULT_LISTENER_ID,
+    )?;
     server.set_port(port);
     Ok(())
 }
@@ -62,17 +181,21 @@ pub fn get_listen_host(server: State<ProxyServer>) -> Result<String, String> {
 }
 
 #[tauri::command]
-pub fn set_listen_host(server: State<ProxyServer>, host: String) -> Result<(), String> {
-    match host.as_str() {
-        "127.0.0.1" | "0.0.0.0" => {
-            server.set_host(host);
-            Ok(())
-        }
-        _ => Err(format!(
-            "Invalid listen host '{}'. Must be '127.0.0.1' or '0.0.0.0'.",
-            host
-        )),
-    }
+pub fn set_listen_host(
+    server: State<ProxyServer>,
+    port_map: State<PortMap>,
+    host: String,
+) -> Result<(), String> {
+    validate_listen_host(&host)?;
+    check_port_free(
+        &server,
+        &port_map,
+        &host,
+        server.get_port(),
+        DEFAULT_LISTENER_ID,
+    )?;
+    server.set_host(host);
+    Ok(())
 }
 
 #[tauri::command]
@@ -88,25 +211,807 @@ pub fn set_rotation_mode(server: State<ProxyServer>, mode: String) -> Result<(),
     Ok(())
 }
 
+/// Upper bound on failover attempts, so one client can't walk a whole pool.
+const MAX_FAILOVER_RETRIES: u32 = 10;
+
+fn validate_failover_retries(retries: u32) -> Result<(), String> {
+    if retries > MAX_FAILOVER_RETRIES {
+        return Err(format!(
+            "Failover retries must be at most {}",
+            MAX_FAILOVER_RETRIES
+        ));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_failover_retries(server: State<ProxyServer>) -> Result<u32, String> {
+    Ok(server.get_failover_retries())
+}
+
+#[tauri::command]
+pub fn set_failover_retries(server: State<ProxyServer>, retries: u32) -> Result<(), String> {
+    validate_failover_retries(retries)?;
+    server.set_failover_retries(retries);
+    Ok(())
+}
+
+/// Upper bound on the hedge delay; anything slower isn't worth racing.
+const MAX_HEDGE_DELAY_MS: u32 = 30_000;
+
+fn validate_hedge_delay(delay_ms: u32) -> Result<(), String> {
+    if delay_ms > MAX_HEDGE_DELAY_MS {
+        return Err(format!(
+            "Hedge delay must be at most {} ms",
+            MAX_HEDGE_DELAY_MS
+        ));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_hedge_delay(server: State<ProxyServer>) -> Result<u32, String> {
+    Ok(server.get_hedge_delay_ms())
+}
+
+#[tauri::command]
+pub fn set_hedge_delay(server: State<ProxyServer>, delay_ms: u32) -> Result<(), String> {
+    validate_hedge_delay(delay_ms)?;
+    server.set_hedge_delay_ms(delay_ms);
+    Ok(())
+}
+
+fn parse_fallback(fallback: &str) -> Result<Fallback, String> {
+    Fallback::parse(fallback).ok_or_else(|| format!("Unknown fallback: {}", fallback))
+}
+
+#[tauri::command]
+pub fn get_fallback(server: State<ProxyServer>) -> Result<String, String> {
+    Ok(server.get_fallback())
+}
+
+#[tauri::command]
+pub fn set_fallback(server: State<ProxyServer>, fallback: String) -> Result<(), String> {
+    server.set_fallback(parse_fallback(&fallback)?);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_inbound_protocol(server: State<ProxyServer>) -> Result<String, String> {
+    Ok(server.get_inbound_protocol())
+}
+
+#[tauri::command]
+pub fn set_inbound_protocol(server: State<ProxyServer>, protocol: String) -> Result<(), String> {
+    server.set_inbound_protocol(parse_inbound_protocol(&protocol)?);
+    Ok(())
+}
+
+fn validate_listen_host(host: &str) -> Result<(), String> {
+    match host {
+        "127.0.0.1" | "0.0.0.0" => Ok(()),
+        _ => Err(format!(
+            "Invalid listen host '{}'. Must be '127.0.0.1' or '0.0.0.0'.",
+            host
+        )),
+    }
+}
+
+fn validate_listen_port(port: u16) -> Result<(), String> {
+    if port < 1024 {
+        return Err("Port must be >= 1024".to_string());
+    }
+    Ok(())
+}
+
+/// Rejects a port already used by another listener or by the port-per-proxy
+/// range.
+fn check_port_free(
+    server: &ProxyServer,
+    port_map: &PortMap,
+    host: &str,
+    port: u16,
+    listener_id: &str,
+) -> Result<(), String> {
+    if server.address_taken(host, port, Some(listener_id)) {
+        return Err(format!("Another listener already uses {}:{}", host, port));
+    }
+    if port_map.get_settings().contains(port) {
+        return Err(format!("Port {} is inside the port-per-proxy range", port));
+    }
+    Ok(())
+}
+
+fn parse_inbound_protocol(protocol: &str) -> Result<InboundProtocol, String> {
+    let parsed = InboundProtocol::parse(protocol)
+        .ok_or_else(|| format!("Unknown inbound protocol: {}", protocol))?;
+    if parsed == InboundProtocol::Transparent && !cfg!(target_os = "linux") {
+        return Err("Transparent mode is only supported on Linux".to_string());
+    }
+    Ok(parsed)
+}
+
+// ─── Listeners ──────────────────────────────────────────────────────────────
+
+/// Validates the user-editable part of a listener configuration.
+#[allow(clippy::too_many_arguments)]
+fn build_listener_config(
+    server: &ProxyServer,
+    port_map: &PortMap,
+    groups: &Groups,
+    id: String,
+    name: String,
+    host: String,
+    port: u16,
+    protocol: Option<String>,
+    rotation_mode: Option<String>,
+    proxy_ids: Option<Vec<String>>,
+    fallback: Option<String>,
+    failover_retries: Option<u32>,
+    hedge_delay_ms: Option<u32>,
+    group: Option<String>,
+) -> Result<ListenerConfig, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Listener name must not be empty".to_string());
+    }
+    validate_listen_host(&host)?;
+    validate_listen_port(port)?;
+    check_port_free(server, port_map, &host, port, &id)?;
+    let protocol = match protocol {
+        Some(p) => parse_inbound_protocol(&p)?,
+        None => InboundProtocol::default(),
+    };
+    let rotation_mode = match rotation_mode {
+        Some(m) => {
+            RotationMode::from_str(&m).ok_or_else(|| format!("Unknown rotation mode: {}", m))?
+        }
+        None => RotationMode::default(),
+    };
+    let fallback = match fallback {
+        Some(f) => parse_fallback(&f)?,
+        None => Fallback::default(),
+    };
+    let failover_retries = failover_retries.unwrap_or(DEFAULT_FAILOVER_RETRIES);
+    validate_failover_retries(failover_retries)?;
+    let hedge_delay_ms = hedge_delay_ms.unwrap_or(0);
+    validate_hedge_delay(hedge_delay_ms)?;
+    if let Some(id) = group.as_ref().filter(|id| !groups.contains(id)) {
+        return Err(format!("Group with id {} not found", id));
+    }
+    Ok(ListenerConfig {
+        id,
+        name,
+        host,
+        port,
+        protocol,
+        rotation_mode,
+        proxy_ids: proxy_ids.unwrap_or_default(),
+        fallback,
+        failover_retries,
+        hedge_delay_ms,
+        group,
+    })
+}
+
+#[tauri::command]
+pub fn list_listeners(server: State<ProxyServer>) -> Result<Vec<ListenerInfo>, String> {
+    Ok(server.list_listeners())
+}
+
 #[tauri::command]
-pub fn remove_proxy(pool: State<ProxyPool>, id: String) -> Result<(), String> {
-    pool.proxies.remove(&id);
+#[allow(clippy::too_many_arguments)]
+pub fn create_listener(
+    server: State<ProxyServer>,
+    port_map: State<PortMap>,
+    groups: State<Groups>,
+    name: String,
+    host: String,
+    port: u16,
+    protocol: Option<String>,
+    rotation_mode: Option<String>,
+    proxy_ids: Option<Vec<String>>,
+    fallback: Option<String>,
+    failover_retries: Option<u32>,
+    hedge_delay_ms: Option<u32>,
+    group: Option<String>,
+) -> Result<String, String> {
+    let id = Uuid::new_v4().to_string();
+    let config = build_listener_config(
+        &server,
+        &port_map,
+        &groups,
+        id.clone(),
+        name,
+        host,
+        port,
+        protocol,
+        rotation_mode,
+        proxy_ids,
+        fallback,
+        failover_retries,
+        hedge_delay_ms,
+        group,
+    )?;
+    server.create_listener(config);
+    Ok(id)
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub fn update_listener(
+    server: State<ProxyServer>,
+    port_map: State<PortMap>,
+    groups: State<Groups>,
+    id: String,
+    name: String,
+    host: String,
+    port: u16,
+    protocol: Option<String>,
+    rotation_mode: Option<String>,
+    proxy_ids: Option<Vec<String>>,
+    fallback: Option<String>,
+    failover_retries: Option<u32>,
+    hedge_delay_ms: Option<u32>,
+    group: Option<String>,
+) -> Result<(), String> {
+    if server.get_listener(&id).is_none() {
+        return Err(format!("Listener with id {} not found", id));
+    }
+    let config = build_listener_config(
+        &server,
+        &port_map,
+        &groups,
+        id.clone(),
+        name,
+        host,
+        port,
+        protocol,
+        rotation_mode,
+        proxy_ids,
+        fallback,
+        failover_retries,
+        hedge_delay_ms,
+        group,
+    )?;
+    if !server.update_listener(config) {
+        return Err(format!("Listener with id {} not found", id));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn delete_listener(server: State<ProxyServer>, id: String) -> Result<(), String> {
+    if id == DEFAULT_LISTENER_ID {
+        return Err("The default listener cannot be deleted".to_string());
+    }
+    if !server.delete_listener(&id) {
+        return Err(format!("Listener with id {} not found", id));
+    }
     Ok(())
 }
 
 #[tauri::command]
-pub fn clear_proxies(pool: State<ProxyPool>) -> Result<(), String> {
-    pool.proxies.clear();
+pub fn start_listener(server: State<ProxyServer>, id: String) -> Result<(), String> {
+    if !server.start_listener(&id) {
+        return Err(format!("Listener with id {} not found", id));
+    }
     Ok(())
 }
 
 #[tauri::command]
-pub fn clear_dead_proxies(pool: State<ProxyPool>) -> Result<(), String> {
-    pool.proxies.retain(|_, p| p.is_alive);
+pub fn stop_listener(server: State<ProxyServer>, id: String) -> Result<(), String> {
+    if !server.stop_listener(&id) {
+        return Err(format!("Listener with id {} not found", id));
+    }
     Ok(())
 }
 
+// ─── Port-per-proxy ─────────────────────────────────────────────────────────
+
 #[tauri::command]
+pub fn get_port_map_settings(port_map: State<PortMap>) -> Result<PortMapSettings, String> {
+    Ok(port_map.get_settings())
+}
+
+#[tauri::command]
+pub fn set_port_map_settings(
+    server: State<ProxyServer>,
+    port_map: State<PortMap>,
+    enabled: bool,
+    host: String,
+    base_port: u16,
+    size: u16,
+) -> Result<(), String> {
+    // The mapped ports take no inbound auth, so they must not be reachable
+    // from other machines.
+    if host != "127.0.0.1" {
+        return Err(format!(
+            "Invalid port map host '{}'. Mapped ports have no authentication and only listen on '127.0.0.1'.",
+            host
+        ));
+    }
+    let settings = PortMapSettings {
+        enabled,
+        host,
+        base_port,
+        size,
+    };
+    settings.validate_range()?;
+    if enabled {
+        if let Some(l) = server
+            .list_listeners()
+            .into_iter()
+            .find(|l| settings.contains(l.config.port))
+        {
+            return Err(format!(
+                "Listener '{}' already uses port {}",
+                l.config.name, l.config.port
+            ));
+        }
+    }
+    port_map.set_settings(settings);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_port_map(port_map: State<PortMap>) -> Result<Vec<PortMapping>, String> {
+    Ok(port_map.get_mappings())
+}
+
+// ─── Routing rules ──────────────────────────────────────────────────────────
+
+fn validate_rule(
+    server: &ProxyServer,
+    pool: &ProxyPool,
+    groups: &Groups,
+    name: &str,
+    conditions: &[Condition],
+    action: &RuleAction,
+) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("Rule name must not be empty".to_string());
+    }
+    for condition in conditions {
+        if let Condition::Listener(id) = condition {
+            if server.get_listener(id).is_none() {
+                return Err(format!("Listener with id {} not found", id));
+            }
+        }
+    }
+    match action {
+        RuleAction::Proxy(id)
+            if action.builtin_id().is_none() && !pool.proxies.contains_key(id) =>
+        {
+            Err(format!("Proxy with id {} not found", id))
+        }
+        RuleAction::Tag(tag) if tag.trim().is_empty() => Err("Tag actions need a tag".to_string()),
+        RuleAction::Group(id) if !groups.contains(id) => {
+            Err(format!("Group with id {} not found", id))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[tauri::command]
+pub fn get_rules(rules: State<Rules>) -> Result<Vec<Rule>, String> {
+    Ok(rules.list())
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub fn add_rule(
+    server: State<ProxyServer>,
+    pool: State<ProxyPool>,
+    groups: State<Groups>,
+    rules: State<Rules>,
+    name: String,
+    enabled: Option<bool>,
+    conditions: Vec<Condition>,
+    action: RuleAction,
+) -> Result<String, String> {
+    validate_rule(&server, &pool, &groups, &name, &conditions, &action)?;
+    let id = Uuid::new_v4().to_string();
+    rules.add(Rule {
+        id: id.clone(),
+        name: name.trim().to_string(),
+        enabled: enabled.unwrap_or(true),
+        conditions,
+        action,
+    })?;
+    Ok(id)
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub fn update_rule(
+    server: State<ProxyServer>,
+    pool: State<ProxyPool>,
+    groups: State<Groups>,
+    rules: State<Rules>,
+    id: String,
+    name: String,
+    enabled: bool,
+    conditions: Vec<Condition>,
+    action: RuleAction,
+) -> Result<(), String> {
+    validate_rule(&server, &pool, &groups, &name, &conditions, &action)?;
+    let updated = rules.update(Rule {
+        id: id.clone(),
+        name: name.trim().to_string(),
+        enabled,
+        conditions,
+        action,
+    })?;
+    if !updated {
+        return Err(format!("Rule with id {} not found", id));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn remove_rule(rules: State<Rules>, id: String) -> Result<(), String> {
+    if !rules.remove(&id) {
+        return Err(format!("Rule with id {} not found", id));
+    }
+    Ok(())
+}
+
+/// Moves a rule to `index` in the evaluation order (0 = checked first).
+#[tauri::command]
+pub fn move_rule(rules: State<Rules>, id: String, index: usize) -> Result<(), String> {
+    if !rules.move_to(&id, index) {
+        return Err(format!("Rule with id {} not found", id));
+    }
+    Ok(())
+}
+
+/// The rule a connection to `host:port` would match, if any.
+#[tauri::command]
+pub fn match_rule(
+    rules: State<Rules>,
+    host: String,
+    port: u16,
+    listener_id: Option<String>,
+) -> Result<Option<Rule>, String> {
+    Ok(rules.find(listener_id.as_deref(), &host, port))
+}
+
+#[tauri::command]
+pub fn set_proxy_tags(pool: State<ProxyPool>, id: String, tags: Vec<String>) -> Result<(), String> {
+    match pool.proxies.get_mut(&id) {
+        Some(mut p) => {
+            p.tags = normalize_tags(tags);
+            Ok(())
+        }
+        None => Err(format!("Proxy with id {} not found", id)),
+    }
+}
+
+/// Trims, drops empty and duplicate tags, and sorts them.
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// ─── Proxy groups ───────────────────────────────────────────────────────────
+
+fn build_group_config(
+    id: String,
+    name: String,
+    tags: Vec<String>,
+    rotation_mode: Option<String>,
+) -> Result<GroupConfig, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Group name must not be empty".to_string());
+    }
+    let tags = normalize_tags(tags);
+    if tags.is_empty() {
+        return Err("A group needs at least one tag".to_string());
+    }
+    let rotation_mode = match rotation_mode {
+        Some(m) => {
+            RotationMode::from_str(&m).ok_or_else(|| format!("Unknown rotation mode: {}", m))?
+        }
+        None => RotationMode::default(),
+    };
+    Ok(GroupConfig {
+        id,
+        name,
+        tags,
+        rotation_mode,
+    })
+}
+
+#[tauri::command]
+pub fn list_groups(
+    pool: State<ProxyPool>,
+    groups: State<Groups>,
+) -> Result<Vec<GroupInfo>, String> {
+    Ok(groups.list_info(&pool))
+}
+
+#[tauri::command]
+pub fn add_group(
+    groups: State<Groups>,
+    name: String,
+    tags: Vec<String>,
+    rotation_mode: Option<String>,
+) -> Result<String, String> {
+    let id = Uuid::new_v4().to_string();
+    groups.add(build_group_config(id.clone(), name, tags, rotation_mode)?);
+    Ok(id)
+}
+
+#[tauri::command]
+pub fn update_group(
+    groups: State<Groups>,
+    id: String,
+    name: String,
+    tags: Vec<String>,
+    rotation_mode: Option<String>,
+) -> Result<(), String> {
+    if !groups.update(build_group_config(id.clone(), name, tags, rotation_mode)?) {
+        return Err(format!("Group with id {} not found", id));
+    }
+    Ok(())
+}
+
+/// Deletes a group no listener or rule points at.
+#[tauri::command]
+pub fn remove_group(
+    server: State<ProxyServer>,
+    rules: State<Rules>,
+    groups: State<Groups>,
+    id: String,
+) -> Result<(), String> {
+    if let Some(l) = server
+        .list_listeners()
+        .into_iter()
+        .find(|l| l.config.group.as_ref() == Some(&id))
+    {
+        return Err(format!("Listener '{}' uses this group", l.config.name));
+    }
+    if let Some(rule) = rules
+        .list()
+        .into_iter()
+        .find(|r| r.action == RuleAction::Group(id.clone()))
+    {
+        return Err(format!("Rule '{}' routes to this group", rule.name));
+    }
+    if !groups.remove(&id) {
+        return Err(format!("Group with id {} not found", id));
+    }
+    Ok(())
+}
+
+// ─── GeoIP ──────────────────────────────────────────────────────────────────
+
+#[tauri::command]
+pub fn get_geoip_databases(geoip: State<GeoIp>) -> Result<Vec<DatabaseStatus>, String> {
+    Ok(geoip.status())
+}
+
+/// Sets the `.mmdb` files proxies are located with and re-locates every
+/// proxy. Returns each file's status; ones that failed to open carry an error.
+#[tauri::command]
+pub fn set_geoip_databases(
+    pool: State<ProxyPool>,
+    geoip: State<GeoIp>,
+    paths: Vec<String>,
+) -> Result<Vec<DatabaseStatus>, String> {
+    let mut seen = HashSet::new();
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty() && seen.insert(p.clone()))
+        .collect();
+    geoip.set_databases(paths);
+    geoip.enrich_all(&pool);
+    Ok(geoip.status())
+}
+
+/// Re-reads the configured databases from disk, e.g. after an update.
+#[tauri::command]
+pub fn reload_geoip(
+    pool: State<ProxyPool>,
+    geoip: State<GeoIp>,
+) -> Result<Vec<DatabaseStatus>, String> {
+    geoip.reload();
+    geoip.enrich_all(&pool);
+    Ok(geoip.status())
+}
+
+// ─── Warm pool ──────────────────────────────────────────────────────────────
+
+/// Upper bound on idle connections per proxy. The total across proxies is
+/// capped separately by `warm::MAX_WARM_CONNECTIONS`.
+const MAX_WARM_POOL_SIZE: u16 = 16;
+
+#[tauri::command]
+pub fn get_warm_pool_settings(warm: State<WarmPool>) -> Result<WarmPoolSettings, String> {
+    Ok(warm.get_settings())
+}
+
+#[tauri::command]
+pub fn set_warm_pool_settings(
+    warm: State<WarmPool>,
+    size: u16,
+    idle_timeout_secs: u64,
+) -> Result<(), String> {
+    if size > MAX_WARM_POOL_SIZE {
+        return Err(format!(
+            "Warm pool size must be at most {}",
+            MAX_WARM_POOL_SIZE
+        ));
+    }
+    if !(1..=300).contains(&idle_timeout_secs) {
+        return Err("Idle timeout must be between 1 and 300 seconds".to_string());
+    }
+    warm.set_settings(WarmPoolSettings {
+        size,
+        idle_timeout_secs,
+    });
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_auth_users(server: State<ProxyServer>) -> Result<Vec<AuthUser>, String> {
+    Ok(server.get_auth_users())
+}
+
+#[tauri::command]
+pub fn set_auth_user(
+    server: State<ProxyServer>,
+    username: String,
+    password: String,
+) -> Result<(), String> {
+    // RFC 1929 encodes both fields with a single length byte.
+    if username.is_empty() || username.len() > 255 {
+        return Err("Username must be between 1 and 255 bytes".to_string());
+    }
+    if password.is_empty() || password.len() > 255 {
+        return Err("Password must be between 1 and 255 bytes".to_string());
+    }
+    server.set_auth_user(username, password);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn remove_auth_user(server: State<ProxyServer>, username: String) -> Result<(), String> {
+    if !server.remove_auth_user(&username) {
+        return Err(format!("Auth user {} not found", username));
+    }
+    Ok(())
+}
+
+/// What keeps proxy `id` from being removed: a chain using it as a hop, a
+/// rule routing to it or a listener limited to it. Chains in `removing` go
+/// along with it and don't count.
+fn proxy_user(
+    server: &ProxyServer,
+    pool: &ProxyPool,
+    rules: &Rules,
+    id: &str,
+    removing: &HashSet<String>,
+) -> Option<String> {
+    if let Some(chain) = pool
+        .proxies
+        .iter()
+        .find(|c| c.is_chain() && !removing.contains(&c.id) && c.hops.iter().any(|h| h == id))
+    {
+        return Some(format!("Chain '{}' uses this proxy", chain.host));
+    }
+    if let Some(rule) = rules
+        .list()
+        .into_iter()
+        .find(|r| matches!(&r.action, RuleAction::Proxy(target) if target == id))
+    {
+        return Some(format!("Rule '{}' routes to this proxy", rule.name));
+    }
+    if let Some(l) = server
+        .list_listeners()
+        .into_iter()
+        .find(|l| l.config.proxy_ids.iter().any(|p| p == id))
+    {
+        return Some(format!("Listener '{}' uses this proxy", l.config.name));
+    }
+    None
+}
+
+fn remove_proxies(pool: &ProxyPool, ids: &HashSet<String>) {
+    for id in ids {
+        if let Some((_, p)) = pool.proxies.remove(id) {
+            crate::ssh::close_session(&p);
+        }
+    }
+}
+
+/// Deletes a proxy no chain, rule or listener points at.
+#[tauri::command]
+pub fn remove_proxy(
+    server: State<ProxyServer>,
+    pool: State<ProxyPool>,
+    rules: State<Rules>,
+    id: String,
+) -> Result<(), String> {
+    if let Some(reason) = proxy_user(&server, &pool, &rules, &id, &HashSet::new()) {
+        return Err(reason);
+    }
+    remove_proxies(&pool, &HashSet::from([id]));
+    Ok(())
+}
+
+/// Deletes every proxy, unless a rule or listener points at one of them.
+#[tauri::command]
+pub fn clear_proxies(
+    server: State<ProxyServer>,
+    pool: State<ProxyPool>,
+    rules: State<Rules>,
+) -> Result<(), String> {
+    let all: HashSet<String> = pool.proxies.iter().map(|p| p.id.clone()).collect();
+    if let Some(reason) = all
+        .iter()
+        .find_map(|id| proxy_user(&server, &pool, &rules, id, &all))
+    {
+        return Err(reason);
+    }
+    remove_proxies(&pool, &all);
+    Ok(())
+}
+
+/// Deletes dead proxies, keeping those a kept chain, a rule or a listener
+/// still points at. Returns how many were kept for that reason.
+#[tauri::command]
+pub fn clear_dead_proxies(
+    server: State<ProxyServer>,
+    pool: State<ProxyPool>,
+    rules: State<Rules>,
+) -> Result<usize, String> {
+    let dead: HashSet<String> = pool
+        .proxies
+        .iter()
+        .filter(|p| !p.is_alive)
+        .map(|p| p.id.clone())
+        .collect();
+    // Keeping a dead chain keeps its hops, so repeat until nothing changes.
+    let mut removing = dead.clone();
+    loop {
+        let kept: Vec<String> = removing
+            .iter()
+            .filter(|id| proxy_user(&server, &pool, &rules, id, &removing).is_some())
+            .cloned()
+            .collect();
+        if kept.is_empty() {
+            break;
+        }
+        for id in kept {
+            removing.remove(&id);
+        }
+    }
+    remove_proxies(&pool, &removing);
+    Ok(dead.len() - removing.len())
+}
+
+/// Applies an optional edit: `None` keeps the current value and an empty
+/// string clears it.
+fn merge_optional(field: &mut Option<String>, value: Option<String>) {
+    if let Some(value) = value {
+        *field = (!value.is_empty()).then_some(value);
+    }
+}
+
+/// Edits a proxy. Optional fields left out keep their current value; pass an
+/// empty `cipher`, `private_key` or `host_key` to clear it.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub fn update_proxy(
     pool: State<ProxyPool>,
     id: String,
@@ -115,15 +1020,47 @@ pub fn update_proxy(
     port: u16,
     user: Option<String>,
     pass: Option<String>,
+    skip_tls_verify: Option<bool>,
+    cipher: Option<String>,
+    private_key: Option<String>,
+    host_key: Option<String>,
+    headers: Option<BTreeMap<String, String>>,
+    tags: Option<Vec<String>>,
 ) -> Result<(), String> {
+    let protocol = protocol.unwrap_or_else(|| "socks5".to_string());
+    if let Some(headers) = &headers {
+        crate::http_connect::validate_headers(headers)?;
+    }
     if let Some(mut p) = pool.proxies.get_mut(&id) {
-        p.protocol = protocol.unwrap_or_else(|| "socks5".to_string());
+        if p.is_chain() {
+            return Err(format!("Proxy with id {} is a chain; use update_chain", id));
+        }
+        let mut new_cipher = p.cipher.clone();
+        merge_optional(&mut new_cipher, cipher);
+        validate_protocol(&protocol, new_cipher.as_deref(), user.as_deref())?;
+        crate::ssh::close_session(&p);
+        p.protocol = protocol;
         p.host = host;
         p.port = port;
         p.user = user;
         p.pass = pass;
+        if let Some(skip) = skip_tls_verify {
+            p.skip_tls_verify = skip;
+        }
+        p.cipher = new_cipher;
+        merge_optional(&mut p.private_key, private_key);
+        merge_optional(&mut p.host_key, host_key);
+        if let Some(headers) = headers {
+            p.headers = headers;
+        }
+        if let Some(tags) = tags {
+            p.tags = normalize_tags(tags);
+        }
         p.is_alive = false;
         p.latency_ms = None;
+        p.exit_ip = None;
+        p.host_ip = None;
+        p.geo = None;
     } else {
         return Err(format!("Proxy with id {} not found", id));
     }
@@ -131,13 +1068,20 @@ pub fn update_proxy(
 }
 
 #[tauri::command]
-pub async fn refresh_health(pool: State<'_, ProxyPool>) -> Result<(), String> {
-    crate::engine::check_all_proxies(&pool).await;
+pub async fn refresh_health(
+    pool: State<'_, ProxyPool>,
+    geoip: State<'_, GeoIp>,
+) -> Result<(), String> {
+    crate::engine::check_all_proxies(&pool, &geoip).await;
     Ok(())
 }
 
 #[tauri::command]
-pub async fn refresh_proxy_health(pool: State<'_, ProxyPool>, id: String) -> Result<(), String> {
-    crate::engine::check_single_proxy(&pool, &id).await;
+pub async fn refresh_proxy_health(
+    pool: State<'_, ProxyPool>,
+    geoip: State<'_, GeoIp>,
+    id: String,
+) -> Result<(), String> {
+    crate::engine::check_single_proxy(&pool, &geoip, &id).await;
     Ok(())
 }
@@ -1,107 +1,382 @@
-use crate::state::{Proxy, ProxyPool, RotationMode};
-use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use crate::error::UpstreamError;
+use crate::geoip::GeoIp;
+use crate::groups::Groups;
+use crate::rules::{Rule, RuleAction, Rules};
+use crate::socks;
+use crate::state::{
+    AuthUser, Fallback, InboundProtocol, ListenerConfig, ListenerInfo, Proxy, ProxyPool,
+    RotationMode, DEFAULT_FAILOVER_RETRIES,
+};
+use crate::warm::WarmPool;
+use dashmap::DashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
-pub struct ProxyServer {
-    pool: ProxyPool,
+/// Id of the listener created at startup. The single-listener commands
+/// (`toggle_listener`, `set_listen_port`, ...) operate on it.
+pub const DEFAULT_LISTENER_ID: &str = "default";
+
+/// Runtime state of one listener. Every field is shared, so clones taken out
+/// of the map observe the same listener.
+#[derive(Clone)]
+struct Listener {
+    config: Arc<Mutex<ListenerConfig>>,
     is_running: Arc<AtomicBool>,
-    pub listen_port: Arc<AtomicU16>,
-    pub listen_host: Arc<Mutex<String>>,
-    pub rotation_mode: Arc<Mutex<RotationMode>>,
     round_robin_idx: Arc<AtomicUsize>,
 }
 
-impl ProxyServer {
-    pub fn new(pool: ProxyPool, default_port: u16) -> Self {
+impl Listener {
+    fn new(config: ListenerConfig) -> Self {
         Self {
-            pool,
+            config: Arc::new(Mutex::new(config)),
             is_running: Arc::new(AtomicBool::new(false)),
-            listen_port: Arc::new(AtomicU16::new(default_port)),
-            listen_host: Arc::new(Mutex::new("127.0.0.1".to_string())),
-            rotation_mode: Arc::new(Mutex::new(RotationMode::RoundRobin)),
             round_robin_idx: Arc::new(AtomicUsize::new(0)),
         }
     }
 
+    fn info(&self) -> ListenerInfo {
+        ListenerInfo {
+            config: self.config.lock().unwrap().clone(),
+            is_running: self.is_running.load(Ordering::SeqCst),
+        }
+    }
+}
+
+pub struct ProxyServer {
+    pool: ProxyPool,
+    listeners: Arc<DashMap<String, Listener>>,
+    /// Inbound SOCKS5 credentials (username → password), shared by every
+    /// listener. When empty, listeners accept unauthenticated clients.
+    pub auth_users: Arc<DashMap<String, String>>,
+    rules: Rules,
+    groups: Groups,
+    warm: WarmPool,
+}
+
+impl ProxyServer {
+    pub fn new(
+        pool: ProxyPool,
+        rules: Rules,
+        groups: Groups,
+        warm: WarmPool,
+        default_port: u16,
+    ) -> Self {
+        let listeners = DashMap::new();
+        listeners.insert(
+            DEFAULT_LISTENER_ID.to_string(),
+            Listener::new(ListenerConfig {
+                id: DEFAULT_LISTENER_ID.to_string(),
+                name: "Default".to_string(),
+                host: "127.0.0.1".to_string(),
+                port: default_port,
+                protocol: InboundProtocol::Auto,
+                rotation_mode: RotationMode::RoundRobin,
+                proxy_ids: Vec::new(),
+                fallback: Fallback::None,
+                failover_retries: DEFAULT_FAILOVER_RETRIES,
+                hedge_delay_ms: 0,
+                group: None,
+            }),
+        );
+        Self {
+            pool,
+            listeners: Arc::new(listeners),
+            auth_users: Arc::new(DashMap::new()),
+            rules,
+            groups,
+            warm,
+        }
+    }
+
+    fn listener(&self, id: &str) -> Option<Listener> {
+        self.listeners.get(id).map(|l| l.value().clone())
+    }
+
+    fn default_listener(&self) -> Listener {
+        self.listener(DEFAULT_LISTENER_ID)
+            .expect("the default listener is never removed")
+    }
+
+    fn update_default(&self, f: impl FnOnce(&mut ListenerConfig)) {
+        f(&mut self.default_listener().config.lock().unwrap());
+    }
+
+    // ── Default listener ────────────────────────────────────────────────────
+
     pub fn get_port(&self) -> u16 {
-        self.listen_port.load(Ordering::SeqCst)
+        self.default_listener().config.lock().unwrap().port
     }
 
     pub fn set_port(&self, port: u16) {
-        self.listen_port.store(port, Ordering::SeqCst);
+        self.update_default(|c| c.port = port);
     }
 
     pub fn get_host(&self) -> String {
-        self.listen_host.lock().unwrap().clone()
+        self.default_listener().config.lock().unwrap().host.clone()
     }
 
     pub fn set_host(&self, host: String) {
-        *self.listen_host.lock().unwrap() = host;
+        self.update_default(|c| c.host = host);
     }
 
     pub fn is_running(&self) -> bool {
-        self.is_running.load(Ordering::SeqCst)
+        self.default_listener().is_running.load(Ordering::SeqCst)
     }
 
     pub fn get_rotation_mode(&self) -> String {
-        self.rotation_mode.lock().unwrap().as_str().to_string()
+        let listener = self.default_listener();
+        let config = listener.config.lock().unwrap();
+        config.rotation_mode.as_str().to_string()
     }
 
     pub fn set_rotation_mode(&self, mode: RotationMode) {
-        *self.rotation_mode.lock().unwrap() = mode;
+        self.update_default(|c| c.rotation_mode = mode);
+    }
+
+    pub fn get_fallback(&self) -> String {
+        let listener = self.default_listener();
+        let config = listener.config.lock().unwrap();
+        config.fallback.as_str().to_string()
+    }
+
+    pub fn set_fallback(&self, fallback: Fallback) {
+        self.update_default(|c| c.fallback = fallback);
+    }
+
+    pub fn get_failover_retries(&self) -> u32 {
+        self.default_listener()
+            .config
+            .lock()
+            .unwrap()
+            .failover_retries
+    }
+
+    pub fn set_failover_retries(&self, retries: u32) {
+        self.update_default(|c| c.failover_retries = retries);
+    }
+
+    pub fn get_hedge_delay_ms(&self) -> u32 {
+        self.default_listener()
+            .config
+            .lock()
+            .unwrap()
+            .hedge_delay_ms
+    }
+
+    pub fn set_hedge_delay_ms(&self, delay_ms: u32) {
+        self.update_default(|c| c.hedge_delay_ms = delay_ms);
+    }
+
+    pub fn get_inbound_protocol(&self) -> String {
+        let listener = self.default_listener();
+        let config = listener.config.lock().unwrap();
+        config.protocol.as_str().to_string()
+    }
+
+    pub fn set_inbound_protocol(&self, protocol: InboundProtocol) {
+        self.update_default(|c| c.protocol = protocol);
     }
 
     pub fn start(&self) {
-        if self.is_running.swap(true, Ordering::SeqCst) {
-            return;
+        self.start_listener(DEFAULT_LISTENER_ID);
+    }
+
+    pub fn stop(&self) {
+        self.stop_listener(DEFAULT_LISTENER_ID);
+    }
+
+    // ── Inbound users ───────────────────────────────────────────────────────
+
+    pub fn get_auth_users(&self) -> Vec<AuthUser> {
+        let mut users: Vec<AuthUser> = self
+            .auth_users
+            .iter()
+            .map(|kv| AuthUser {
+                username: kv.key().clone(),
+                password: kv.value().clone(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    pub fn set_auth_user(&self, username: String, password: String) {
+        self.auth_users.insert(username, password);
+    }
+
+    pub fn remove_auth_user(&self, username: &str) -> bool {
+        self.auth_users.remove(username).is_some()
+    }
+
+    // ── Listeners ───────────────────────────────────────────────────────────
+
+    /// All listeners, the default one first, then by name.
+    pub fn list_listeners(&self) -> Vec<ListenerInfo> {
+        let mut listeners: Vec<ListenerInfo> =
+            self.listeners.iter().map(|kv| kv.value().info()).collect();
+        listeners.sort_by(|a, b| {
+            (a.config.id != DEFAULT_LISTENER_ID)
+                .cmp(&(b.config.id != DEFAULT_LISTENER_ID))
+                .then_with(|| a.config.name.cmp(&b.config.name))
+        });
+        listeners
+    }
+
+    pub fn get_listener(&self, id: &str) -> Option<ListenerInfo> {
+        self.listener(id).map(|l| l.info())
+    }
+
+    /// Whether another listener is already configured for `host:port`.
+    /// `0.0.0.0` overlaps every address on the same port.
+    pub fn address_taken(&self, host: &str, port: u16, except_id: Option<&str>) -> bool {
+        self.listeners.iter().any(|kv| {
+            if Some(kv.key().as_str()) == except_id {
+                return false;
+            }
+            let c = kv.value().config.lock().unwrap();
+            c.port == port && (c.host == host || c.host == "0.0.0.0" || host == "0.0.0.0")
+        })
+    }
+
+    pub fn create_listener(&self, config: ListenerConfig) {
+        self.listeners
+            .insert(config.id.clone(), Listener::new(config));
+    }
+
+    /// Replaces a listener's configuration. Rotation mode and proxy subset
+    /// apply to the next connection; address and protocol changes take effect
+    /// when the listener is next started.
+    pub fn update_listener(&self, config: ListenerConfig) -> bool {
+        match self.listener(&config.id) {
+            Some(l) => {
+                *l.config.lock().unwrap() = config;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn delete_listener(&self, id: &str) -> bool {
+        match self.listeners.remove(id) {
+            Some((_, l)) => {
+                l.is_running.store(false, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn start_listener(&self, id: &str) -> bool {
+        let Some(listener) = self.listener(id) else {
+            return false;
+        };
+        if listener.is_running.swap(true, Ordering::SeqCst) {
+            return true;
         }
 
         let pool = self.pool.clone();
-        let running = self.is_running.clone();
-        let port = self.listen_port.load(Ordering::SeqCst);
-        let host = self.listen_host.lock().unwrap().clone();
-        let rotation_mode = self.rotation_mode.clone();
-        let rr_idx = self.round_robin_idx.clone();
-
-        tauri::async_runtime::spawn(async move {
-            let addr = format!("{}:{}", host, port);
-            let listener = match TcpListener::bind(&addr).await {
-                Ok(l) => l,
-                Err(e) => {
-                    eprintln!("Failed to bind {}: {}", addr, e);
-                    running.store(false, Ordering::SeqCst);
-                    return;
-                }
-            };
+        let auth_users = self.auth_users.clone();
+        let rules = self.rules.clone();
+        let groups = self.groups.clone();
+        let warm = self.warm.clone();
+        let (name, host, port, protocol) = {
+            let c = listener.config.lock().unwrap();
+            (c.name.clone(), c.host.clone(), c.port, c.protocol.clone())
+        };
 
-            println!("Local proxy listening on {}", addr);
-
-            while running.load(Ordering::SeqCst) {
-                if let Ok(Ok((mut client_stream, _))) =
-                    tokio::time::timeout(std::time::Duration::from_secs(1), listener.accept()).await
-                {
-                    let p = pool.clone();
-                    let mode = rotation_mode.lock().unwrap().clone();
-                    let idx = rr_idx.clone();
-
-                    tauri::async_runtime::spawn(async move {
-                        if let Err(e) = handle_client(&mut client_stream, p, mode, idx).await {
-                            eprintln!("Client error: {}", e);
-                        }
-                    });
-                }
+        let running = listener.is_running.clone();
+        let context = move || {
+            // Selection settings are read per connection so edits apply
+            // without a restart.
+            let config = listener.config.lock().unwrap().clone();
+            ClientContext {
+                pool: pool.clone(),
+                mode: config.rotation_mode,
+                rr_idx: listener.round_robin_idx.clone(),
+                auth_users: auth_users.clone(),
+                listen_port: port,
+                proxy_ids: config.proxy_ids,
+                fallback: config.fallback,
+                failover_retries: config.failover_retries,
+                hedge_delay: match config.hedge_delay_ms {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms.into())),
+                },
+                listener_id: config.id,
+                rules: Some(rules.clone()),
+                group: config.group,
+                groups: groups.clone(),
+                warm: warm.clone(),
             }
-            println!("Local proxy stopped");
-        });
+        };
+        tauri::async_runtime::spawn(serve_listener(name, host, port, protocol, running, context));
+        true
     }
 
-    pub fn stop(&self) {
-        self.is_running.store(false, Ordering::SeqCst);
+    pub fn stop_listener(&self, id: &str) -> bool {
+        match self.listener(id) {
+            Some(l) => {
+                l.is_running.store(false, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
     }
 }
 
+/// Binds `host:port` and serves clients until `running` is cleared.
+/// `context` builds the handler context for each accepted connection.
+pub(crate) async fn serve_listener(
+    name: String,
+    host: String,
+    port: u16,
+    protocol: InboundProtocol,
+    running: Arc<AtomicBool>,
+    context: impl Fn() -> ClientContext,
+) {
+    let addr = format!("{}:{}", host, port);
+    let bound = if protocol == InboundProtocol::Transparent {
+        crate::transparent::bind_listener(&addr).await
+    } else {
+        TcpListener::bind(&addr).await
+    };
+    let tcp_listener = match bound {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[{}] Failed to bind {}: {}", name, addr, e);
+            running.store(false, Ordering::SeqCst);
+            return;
+        }
+    };
+
+    println!(
+        "[{}] Local {} proxy listening on {}",
+        name,
+        protocol.as_str(),
+        addr
+    );
+
+    while running.load(Ordering::SeqCst) {
+        if let Ok(Ok((mut client_stream, _))) =
+            tokio::time::timeout(std::time::Duration::from_secs(1), tcp_listener.accept()).await
+        {
+            let ctx = context();
+            let protocol = protocol.clone();
+
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = handle_client(&mut client_stream, ctx, protocol).await {
+                    eprintln!("Client error: {}", e);
+                }
+            });
+        }
+    }
+    println!("[{}] Local proxy stopped", name);
+}
+
 // ─── Proxy selection ────────────────────────────────────────────────────────
 
 /// Simple djb2 hash for stable, dependency-free string hashing.
@@ -202,12 +477,221 @@ fn select_proxy<'a>(
 
 // ─── Client handler ─────────────────────────────────────────────────────────
 
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+/// RFC 1929 username/password sub-negotiation. Returns `Ok(true)` when the
+/// supplied credentials match one of the configured users.
+async fn authenticate_client(
+    client: &mut TcpStream,
+    auth_users: &DashMap<String, String>,
+) -> std::io::Result<bool> {
+    let mut buf = [0u8; 256];
+    client.read_exact(&mut buf[0..2]).await?;
+    if buf[0] != 0x01 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid auth sub-negotiation version",
+        ));
+    }
+    let ulen = buf[1] as usize;
+    client.read_exact(&mut buf[0..ulen]).await?;
+    let username = String::from_utf8_lossy(&buf[0..ulen]).into_owned();
+
+    client.read_exact(&mut buf[0..1]).await?;
+    let plen = buf[0] as usize;
+    client.read_exact(&mut buf[0..plen]).await?;
+    let password = String::from_utf8_lossy(&buf[0..plen]).into_owned();
+
+    let ok = auth_users
+        .get(&username)
+        .map(|p| *p.value() == password)
+        .unwrap_or(false);
+
+    client
+        .write_all(&[0x01, if ok { 0x00 } else { 0x01 }])
+        .await?;
+    Ok(ok)
+}
+
+/// Per-connection snapshot of everything a client handler needs.
+#[derive(Clone)]
+pub(crate) struct ClientContext {
+    pub pool: ProxyPool,
+    pub mode: RotationMode,
+    pub rr_idx: Arc<AtomicUsize>,
+    pub auth_users: Arc<DashMap<String, String>>,
+    pub listen_port: u16,
+    /// The listener's proxy subset; empty means the whole pool.
+    pub proxy_ids: Vec<String>,
+    /// Used when no proxy in the subset is alive.
+    pub fallback: Fallback,
+    /// Further upstreams to try after the selected one fails.
+    pub failover_retries: u32,
+    /// When set, a second upstream is raced against the selected one if it
+    /// hasn't opened its tunnel after this long.
+    pub hedge_delay: Option<Duration>,
+    /// The listener the client connected to, for `listener` rule conditions.
+    pub listener_id: String,
+    /// Routing rules; `None` where the upstream is fixed, as in port-per-proxy.
+    pub rules: Option<Rules>,
+    /// The listener's proxy group, used instead of `proxy_ids` when set.
+    pub group: Option<String>,
+    pub groups: Groups,
+    pub warm: WarmPool,
+}
+
+impl ClientContext {
+    /// A context drawing on all of `pool` with one failover retry and no
+    /// rules, groups or inbound auth.
+    #[cfg(test)]
+    pub(crate) fn for_pool(pool: ProxyPool) -> Self {
+        Self {
+            pool,
+            mode: RotationMode::RoundRobin,
+            rr_idx: Default::default(),
+            auth_users: Default::default(),
+            listen_port: 0,
+            proxy_ids: Vec::new(),
+            fallback: Fallback::None,
+            failover_retries: 1,
+            hedge_delay: None,
+            listener_id: String::new(),
+            rules: None,
+            group: None,
+            groups: Default::default(),
+            warm: Default::default(),
+        }
+    }
+
+    /// The first routing rule matching a connection to `target_host:target_port`.
+    fn route(&self, target_host: &str, target_port: u16) -> Option<Rule> {
+        self.rules
+            .as_ref()?
+            .find(Some(&self.listener_id), target_host, target_port)
+    }
+}
+
 async fn handle_client(
     client: &mut TcpStream,
-    pool: ProxyPool,
-    mode: RotationMode,
-    rr_idx: Arc<AtomicUsize>,
+    ctx: ClientContext,
+    protocol: InboundProtocol,
 ) -> std::io::Result<()> {
+    match protocol {
+        InboundProtocol::Auto => {
+            // Peek so the chosen handler still sees the full handshake.
+            let mut first = [0u8; 1];
+            if client.peek(&mut first).await? == 0 {
+                return Ok(());
+            }
+            match first[0] {
+                0x04 => handle_socks4(client, ctx).await,
+                0x05 => handle_socks5(client, ctx).await,
+                b'A'..=b'Z' | b'a'..=b'z' => crate::http_proxy::handle_http(client, ctx).await,
+                b => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unrecognised protocol (first byte 0x{:02x})", b),
+                )),
+            }
+        }
+        InboundProtocol::Socks5 => handle_socks5(client, ctx).await,
+        InboundProtocol::Http => crate::http_proxy::handle_http(client, ctx).await,
+        InboundProtocol::Transparent => {
+            let port = ctx.listen_port;
+            crate::transparent::handle_transparent(client, ctx, port).await
+        }
+    }
+}
+
+/// Reads a NUL-terminated SOCKS4 field (USERID or 4a hostname).
+async fn read_nul_terminated(client: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        client.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            return Ok(out);
+        }
+        if out.len() == 255 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "SOCKS4 field too long",
+            ));
+        }
+        out.push(byte[0]);
+    }
+}
+
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+/// SOCKS4 and SOCKS4a (hostname in place of a `0.0.0.x` address), CONNECT
+/// only. SOCKS4 cannot carry a password, so it is refused whenever inbound
+/// users are configured.
+async fn handle_socks4(client: &mut TcpStream, ctx: ClientContext) -> std::io::Result<()> {
+    let mut buf = [0u8; 8];
+    client.read_exact(&mut buf).await?;
+    if buf[0] != 0x04 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Not SOCKS4",
+        ));
+    }
+    let command = buf[1];
+    let target_port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = std::net::Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+    let _user_id = read_nul_terminated(client).await?;
+
+    // 0.0.0.x with x != 0 marks a SOCKS4a request carrying a hostname.
+    let target_addr = if buf[4..7] == [0, 0, 0] && buf[7] != 0 {
+        String::from_utf8_lossy(&read_nul_terminated(client).await?).into_owned()
+    } else {
+        ip.to_string()
+    };
+
+    let reply = |code: u8| [0x00, code, buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]];
+
+    if command != 0x01 {
+        client.write_all(&reply(SOCKS4_REJECTED)).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Only CONNECT supported",
+        ));
+    }
+    if !ctx.auth_users.is_empty() {
+        client.write_all(&reply(SOCKS4_REJECTED)).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "SOCKS4 cannot authenticate; inbound users are configured",
+        ));
+    }
+
+    let selected = match select_upstream(&ctx, &target_addr, target_port) {
+        Some(p) => p,
+        None => {
+            client.write_all(&reply(SOCKS4_REJECTED)).await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No alive proxies",
+            ));
+        }
+    };
+
+    match dial_upstream(&ctx, selected, &target_addr, target_port).await {
+        Ok(mut tunnel) => {
+            client.write_all(&reply(SOCKS4_GRANTED)).await?;
+            tokio::io::copy_bidirectional(client, &mut tunnel.stream).await?;
+            Ok(())
+        }
+        Err(e) => {
+            client.write_all(&reply(SOCKS4_REJECTED)).await?;
+            Err(e.into())
+        }
+    }
+}
+
+async fn handle_socks5(client: &mut TcpStream, ctx: ClientContext) -> std::io::Result<()> {
     let mut buf = [0u8; 256];
     client.read_exact(&mut buf[0..2]).await?;
     if buf[0] != 0x05 {
@@ -218,258 +702,780 @@ async fn handle_client(
     }
     let n_methods = buf[1] as usize;
     client.read_exact(&mut buf[0..n_methods]).await?;
+    let offered = &buf[0..n_methods];
 
-    // No-auth
-    client.write_all(&[0x05, 0x00]).await?;
+    // Username/password is mandatory as soon as one inbound user exists.
+    let required = if ctx.auth_users.is_empty() {
+        METHOD_NO_AUTH
+    } else {
+        METHOD_USER_PASS
+    };
+    if !offered.contains(&required) {
+        client.write_all(&[0x05, METHOD_NO_ACCEPTABLE]).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "No acceptable authentication method",
+        ));
+    }
+    client.write_all(&[0x05, required]).await?;
 
-    // Read CONNECT request
+    if required == METHOD_USER_PASS && !authenticate_client(client, &ctx.auth_users).await? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "Invalid username or password",
+        ));
+    }
+
+    // Read the request
     client.read_exact(&mut buf[0..4]).await?;
-    if buf[1] != 0x01 {
+    let command = buf[1];
+    if !matches!(
+        buf[3],
+        socks::ATYP_IPV4 | socks::ATYP_DOMAIN | socks::ATYP_IPV6
+    ) {
+        client
+            .write_all(&socks::reply(socks::REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None))
+            .await?;
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
-            "Only CONNECT supported",
+            "Invalid address type",
         ));
     }
+    let (target_addr, target_port) = socks::read_addr(client, buf[3]).await?;
 
-    let target_addr: String;
-    let target_port: u16;
-
-    match buf[3] {
-        0x01 => {
-            client.read_exact(&mut buf[0..4]).await?;
-            let ip = std::net::Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
-            client.read_exact(&mut buf[0..2]).await?;
-            target_port = u16::from_be_bytes([buf[0], buf[1]]);
-            target_addr = ip.to_string();
-        }
-        0x03 => {
-            client.read_exact(&mut buf[0..1]).await?;
-            let len = buf[0] as usize;
-            client.read_exact(&mut buf[0..len]).await?;
-            target_addr = String::from_utf8_lossy(&buf[0..len]).into_owned();
-            client.read_exact(&mut buf[0..2]).await?;
-            target_port = u16::from_be_bytes([buf[0], buf[1]]);
-        }
-        0x04 => {
-            client.read_exact(&mut buf[0..16]).await?;
-            let mut arr = [0u8; 16];
-            arr.copy_from_slice(&buf[0..16]);
-            let ip = std::net::Ipv6Addr::from(arr);
-            client.read_exact(&mut buf[0..2]).await?;
-            target_port = u16::from_be_bytes([buf[0], buf[1]]);
-            target_addr = ip.to_string();
+    match command {
+        socks::CMD_CONNECT => {}
+        socks::CMD_BIND => {
+            return handle_bind(client, ctx, &target_addr, target_port).await;
+        }
+        socks::CMD_UDP_ASSOCIATE => {
+            return crate::udp::handle_udp_associate(client, ctx, &target_addr, target_port).await;
         }
         _ => {
+            client
+                .write_all(&socks::reply(socks::REPLY_COMMAND_NOT_SUPPORTED, None))
+                .await?;
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                "Invalid address type",
-            ))
+                "Unsupported SOCKS5 command",
+            ));
         }
     }
 
-    // Select upstream proxy using current rotation strategy
-    let proxies = pool.get_all();
-    let mut alive_proxies: Vec<Proxy> = proxies.into_iter().filter(|p| p.is_alive).collect();
-    alive_proxies.sort_by(|a, b| a.id.cmp(&b.id));
-
-    let selected = select_proxy(&alive_proxies, &mode, &rr_idx, &target_addr)
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No alive proxies"))?;
-
-    let proxy_addr = format!("{}:{}", selected.host, selected.port);
-    let target = format!("{}:{}", target_addr, target_port);
-
-    println!(
-        "[Proxy] Selected {} ({}) for target {}",
-        selected.id, proxy_addr, target
-    );
-
-    if selected.protocol == "http" || selected.protocol == "https" {
-        let mut st = match tokio::net::TcpStream::connect(&proxy_addr).await {
-            Ok(s) => s,
-            Err(e) => {
-                client
-                    .write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
-                    .await?;
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::ConnectionRefused,
-                    e,
-                ));
-            }
-        };
-
-        let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
-        if let (Some(u), Some(pass)) = (selected.user.clone(), selected.pass.clone()) {
-            use base64::Engine;
-            let auth = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", u, pass));
-            req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", auth));
+    let selected = match select_upstream(&ctx, &target_addr, target_port) {
+        Some(p) => p,
+        None => {
+            client
+                .write_all(&socks::reply(socks::REPLY_GENERAL_FAILURE, None))
+                .await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No alive proxies",
+            ));
         }
-        req.push_str("\r\n");
-        st.write_all(req.as_bytes()).await?;
+    };
 
-        // Read response until \r\n\r\n
-        let mut resp_buf = Vec::new();
-        let mut buf = [0u8; 1];
-        loop {
-            if st.read_exact(&mut buf).await.is_err() {
-                break;
-            }
-            resp_buf.push(buf[0]);
-            if resp_buf.ends_with(b"\r\n\r\n") {
-                break;
-            }
+    match dial_upstream(&ctx, selected, &target_addr, target_port).await {
+        Ok(mut tunnel) => {
+            let reply = match &tunnel.bound {
+                Some((host, port)) => socks::reply_to(socks::REPLY_SUCCEEDED, host, *port),
+                None => socks::reply(socks::REPLY_SUCCEEDED, None),
+            };
+            client.write_all(&reply).await?;
+            tokio::io::copy_bidirectional(client, &mut tunnel.stream).await?;
+            Ok(())
         }
+        Err(e) => {
+            client
+                .write_all(&socks::reply(e.reply_code(), None))
+                .await?;
+            Err(e.into())
+        }
+    }
+}
 
-        if !resp_buf.starts_with(b"HTTP/1.1 200") && !resp_buf.starts_with(b"HTTP/1.0 200") {
-            let err_msg = format!(
-                "HTTP Connect failed: {}",
-                String::from_utf8_lossy(&resp_buf)
-            );
-            eprintln!(
-                "[Proxy] Error connecting through {}: {}",
-                proxy_addr, err_msg
-            );
+/// SOCKS5 BIND, passed through to an upstream SOCKS5 proxy. The upstream's
+/// two replies (listening address, then the accepted peer) are relayed to the
+/// client before the connection becomes a plain tunnel.
+async fn handle_bind(
+    client: &mut TcpStream,
+    ctx: ClientContext,
+    target_addr: &str,
+    target_port: u16,
+) -> std::io::Result<()> {
+    let selected = match select_upstream(&ctx, target_addr, target_port) {
+        Some(p) => p,
+        None => {
             client
-                .write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .write_all(&socks::reply(socks::REPLY_GENERAL_FAILURE, None))
                 .await?;
             return Err(std::io::Error::new(
-                std::io::ErrorKind::ConnectionRefused,
-                err_msg,
+                std::io::ErrorKind::NotFound,
+                "No alive proxies",
             ));
         }
+    };
 
+    if selected.is_reject() {
+        client
+            .write_all(&socks::reply(socks::REPLY_NOT_ALLOWED, None))
+            .await?;
+        return Err(UpstreamError::Rejected.into());
+    }
+    if !selected.is_socks5() {
         client
-            .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .write_all(&socks::reply(socks::REPLY_COMMAND_NOT_SUPPORTED, None))
             .await?;
-        tokio::io::copy_bidirectional(client, &mut st).await?;
-        Ok(())
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "BIND is not supported by {} upstream {}",
+                selected.protocol, selected.id
+            ),
+        ));
+    }
+
+    // Failover only moves on to other SOCKS5 upstreams.
+    let bind = dial_with(
+        &ctx,
+        selected,
+        target_addr,
+        target_port,
+        false,
+        Proxy::is_socks5,
+        |p| async move {
+            let proxy_addr = format!("{}:{}", p.host, p.port);
+            let mut st = TcpStream::connect(&proxy_addr)
+                .await
+                .map_err(UpstreamError::ProxyUnreachable)?;
+            socks::handshake(&mut st, p.user.as_deref(), p.pass.as_deref()).await?;
+            let (host, port) =
+                socks::request(&mut st, socks::CMD_BIND, target_addr, target_port).await?;
+            let host = socks::resolve_unspecified(host, st.peer_addr()?.ip());
+            Ok((st, host, port))
+        },
+    );
+    let (mut st, bound_host, bound_port) = match bind.await {
+        Ok(b) => b,
+        Err(e) => {
+            client
+                .write_all(&socks::reply(e.reply_code(), None))
+                .await?;
+            return Err(e.into());
+        }
+    };
+    client
+        .write_all(&socks::reply_to(
+            socks::REPLY_SUCCEEDED,
+            &bound_host,
+            bound_port,
+        ))
+        .await?;
+
+    // Second reply: the upstream accepted the incoming connection.
+    match socks::read_reply(&mut st).await {
+        Ok((peer_host, peer_port)) => {
+            client
+                .write_all(&socks::reply_to(
+                    socks::REPLY_SUCCEEDED,
+                    &peer_host,
+                    peer_port,
+                ))
+                .await?;
+        }
+        Err(e) => {
+            client
+                .write_all(&socks::reply(e.reply_code(), None))
+                .await?;
+            return Err(e.into());
+        }
+    }
+
+    tokio::io::copy_bidirectional(client, &mut st).await?;
+    Ok(())
+}
+
+// ─── Upstream dialing ───────────────────────────────────────────────────────
+
+/// Any bidirectional byte stream an upstream connection can be relayed over.
+pub(crate) trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub(crate) type UpstreamStream = Box<dyn AsyncStream>;
+
+/// An established tunnel to the target.
+pub(crate) struct Tunnel {
+    pub stream: UpstreamStream,
+    /// `BND.ADDR:BND.PORT` reported by the upstream, when its protocol has one.
+    pub bound: Option<(String, u16)>,
+}
+
+/// Formats `host:port`, bracketing IPv6 literals.
+pub(crate) fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
     } else {
-        let upstream_stream =
-            if let (Some(u), Some(pass)) = (selected.user.clone(), selected.pass.clone()) {
-                tokio_socks::tcp::Socks5Stream::connect_with_password(
-                    proxy_addr.as_str(),
-                    target.as_str(),
-                    &u,
-                    &pass,
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Picks an alive upstream for `target_host:target_port`: the one the first
+/// matching routing rule names, otherwise one of the listener's proxies using
+/// its rotation mode.
+pub(crate) fn select_upstream(
+    ctx: &ClientContext,
+    target_host: &str,
+    target_port: u16,
+) -> Option<Proxy> {
+    select_upstream_where(ctx, target_host, target_port, |_| true)
+}
+
+/// Like [`select_upstream`], restricted to proxies accepted by `filter`.
+pub(crate) fn select_upstream_where(
+    ctx: &ClientContext,
+    target_host: &str,
+    target_port: u16,
+    filter: impl Fn(&Proxy) -> bool,
+) -> Option<Proxy> {
+    let rule = ctx.route(target_host, target_port);
+    if let Some(rule) = &rule {
+        println!(
+            "[Proxy] Rule '{}' matched {}",
+            rule.name,
+            join_host_port(target_host, target_port)
+        );
+    }
+    let action = rule.map(|r| r.action);
+    select_candidate(ctx, target_host, action.as_ref(), &filter)
+        .or_else(|| ctx.fallback.upstream().filter(filter))
+}
+
+/// Picks from the proxies a rule `action` routes to or, without one, the
+/// listener's alive proxies, leaving out penalized ones while there are
+/// others. Never falls back.
+fn select_candidate(
+    ctx: &ClientContext,
+    target_host: &str,
+    action: Option<&RuleAction>,
+    filter: &impl Fn(&Proxy) -> bool,
+) -> Option<Proxy> {
+    // The group to draw from: the rule's, or else the listener's. A group
+    // that no longer exists has no members.
+    let group = match action {
+        Some(RuleAction::Group(id)) => Some(ctx.groups.get(id)),
+        Some(_) => None,
+        None => ctx.group.as_deref().map(|id| ctx.groups.get(id)),
+    };
+    // A rule's target or a group replaces the listener's subset.
+    let in_scope = |p: &Proxy| match (action, &group) {
+        (_, Some(group)) => group.as_ref().is_some_and(|g| g.config.contains(p)),
+        (None, None) => ctx.proxy_ids.is_empty() || ctx.proxy_ids.contains(&p.id),
+        (Some(RuleAction::Proxy(id)), None) => p.id == *id,
+        (Some(RuleAction::Tag(tag)), None) => p.has_tag(tag),
+        (Some(_), None) => false,
+    };
+    let builtin_ids: Vec<&str> = match action {
+        None if group.is_none() => ctx.proxy_ids.iter().map(String::as_str).collect(),
+        None => Vec::new(),
+        Some(action) => action.builtin_id().into_iter().collect(),
+    };
+    let proxies = ctx.pool.get_all();
+    let mut alive_proxies: Vec<Proxy> = proxies
+        .into_iter()
+        .filter(|p| p.is_alive && filter(p) && in_scope(p))
+        .collect();
+    // Built-in upstreams named in the subset are always available.
+    alive_proxies.extend(
+        builtin_ids
+            .into_iter()
+            .filter_map(Proxy::builtin)
+            .filter(|p| filter(p)),
+    );
+    if alive_proxies.iter().any(|p| !ctx.pool.is_penalized(&p.id)) {
+        alive_proxies.retain(|p| !ctx.pool.is_penalized(&p.id));
+    }
+    alive_proxies.sort_by(|a, b| a.id.cmp(&b.id));
+
+    // Groups rotate on their own, whichever listener or rule uses them.
+    let (mode, rr_idx) = match group.flatten() {
+        Some(g) => (g.config.rotation_mode, g.round_robin_idx),
+        None => (ctx.mode.clone(), ctx.rr_idx.clone()),
+    };
+    select_proxy(&alive_proxies, &mode, &rr_idx, target_host).cloned()
+}
+
+/// Opens the raw transport to the upstream proxy itself, before any proxy
+/// protocol has been spoken.
+pub(crate) async fn connect_proxy(selected: &Proxy) -> Result<UpstreamStream, UpstreamError> {
+    let proxy_addr = format!("{}:{}", selected.host, selected.port);
+    let st = tokio::net::TcpStream::connect(&proxy_addr)
+        .await
+        .map_err(UpstreamError::ProxyUnreachable)?;
+    secure_transport(Box::new(st), selected).await
+}
+
+/// Connects to the target from this machine, for the `direct` upstream.
+async fn connect_direct(target_host: &str, target_port: u16) -> Result<Tunnel, UpstreamError> {
+    let st = TcpStream::connect((target_host, target_port))
+        .await
+        .map_err(UpstreamError::TargetUnreachable)?;
+    let local = st.local_addr()?;
+    Ok(Tunnel {
+        stream: Box::new(st),
+        bound: Some((local.ip().to_string(), local.port())),
+    })
+}
+
+/// Wraps a transport to `selected` in TLS when its protocol calls for it.
+pub(crate) async fn secure_transport(
+    st: UpstreamStream,
+    selected: &Proxy,
+) -> Result<UpstreamStream, UpstreamError> {
+    if selected.uses_tls() {
+        return crate::tls::connect(st, &selected.host, !selected.skip_tls_verify).await;
+    }
+    Ok(st)
+}
+
+/// Opens a tunnel to `target_host:target_port` through `selected`. When that
+/// fails, up to `failover_retries` other upstreams are tried in rotation
+/// order before the error is returned; each failing proxy is penalized.
+pub(crate) async fn dial_upstream(
+    ctx: &ClientContext,
+    selected: Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    dial_with(
+        ctx,
+        selected,
+        target_host,
+        target_port,
+        true,
+        |_| true,
+        |p| async move { open_client_tunnel(ctx, &p, target_host, target_port).await },
+    )
+    .await
+}
+
+/// The failover loop behind [`dial_upstream`], for whatever `open` makes of
+/// an upstream. Other upstreams are only tried if `filter` accepts them.
+/// With `hedge`, a slow attempt is raced against a second proxy after the
+/// listener's hedge delay; leave it off when `open` has side effects that
+/// must not happen twice.
+pub(crate) async fn dial_with<T, Fut>(
+    ctx: &ClientContext,
+    mut selected: Proxy,
+    target_host: &str,
+    target_port: u16,
+    hedge: bool,
+    filter: impl Fn(&Proxy) -> bool,
+    open: impl Fn(Proxy) -> Fut,
+) -> Result<T, UpstreamError>
+where
+    Fut: Future<Output = Result<T, UpstreamError>>,
+{
+    let target = join_host_port(target_host, target_port);
+    // Failover stays within what the matching rule routes to.
+    let action = ctx.route(target_host, target_port).map(|r| r.action);
+    let mut failed: Vec<String> = Vec::new();
+    loop {
+        if selected.is_direct() || selected.is_reject() {
+            println!("[Proxy] Selected {} for target {}", selected.id, target);
+        } else {
+            println!(
+                "[Proxy] Selected {} ({}:{}) for target {}",
+                selected.id, selected.host, selected.port, target
+            );
+        }
+        let (used, result) = match ctx.hedge_delay {
+            Some(delay) if hedge && !selected.is_direct() && !selected.is_reject() => {
+                open_hedged(
+                    ctx,
+                    action.as_ref(),
+                    selected,
+                    target_host,
+                    delay,
+                    &mut failed,
+                    &filter,
+                    &open,
                 )
                 .await
-            } else {
-                tokio_socks::tcp::Socks5Stream::connect(proxy_addr.as_str(), target.as_str()).await
-            };
+            }
+            _ => {
+                let result = open(selected.clone()).await;
+                (selected, result)
+            }
+        };
+        let e = match result {
+            Ok(tunnel) => {
+                ctx.pool.clear_penalty(&used.id);
+                return Ok(tunnel);
+            }
+            Err(e) => e,
+        };
+
+        note_failure(&ctx.pool, &used, &e);
+        // A policy decision is final.
+        if matches!(e, UpstreamError::Rejected) || failed.len() as u32 >= ctx.failover_retries {
+            return Err(e);
+        }
+        failed.push(used.id.clone());
+        let next = select_candidate(ctx, target_host, action.as_ref(), &|p: &Proxy| {
+            filter(p) && !failed.contains(&p.id)
+        });
+        selected = match next {
+            Some(next) => next,
+            None => return Err(e),
+        };
+    }
+}
+
+/// Penalizes `proxy` if `e` is its own fault.
+fn note_failure(pool: &ProxyPool, proxy: &Proxy, e: &UpstreamError) {
+    if e.is_proxy_fault() {
+        let cooldown = pool.penalize(&proxy.id);
+        eprintln!(
+            "[Proxy] {} failed ({}), sidelined for {}s",
+            proxy.id,
+            e,
+            cooldown.as_secs()
+        );
+    }
+}
+
+/// Opens a tunnel through `primary`, racing a second pool proxy against it
+/// once `delay` has passed without a tunnel. The first tunnel wins and the
+/// other attempt is dropped, closing its connection. A racer that fails
+/// while the other is still going is recorded in `failed`; the returned
+/// proxy is the one the result came from.
+#[allow(clippy::too_many_arguments)]
+async fn open_hedged<T, Fut>(
+    ctx: &ClientContext,
+    action: Option<&RuleAction>,
+    primary: Proxy,
+    target_host: &str,
+    delay: Duration,
+    failed: &mut Vec<String>,
+    filter: &impl Fn(&Proxy) -> bool,
+    open: &impl Fn(Proxy) -> Fut,
+) -> (Proxy, Result<T, UpstreamError>)
+where
+    Fut: Future<Output = Result<T, UpstreamError>>,
+{
+    let first = open(primary.clone());
+    tokio::pin!(first);
+    if let Ok(result) = tokio::time::timeout(delay, &mut first).await {
+        return (primary.clone(), result);
+    }
 
-        match upstream_stream {
-            Ok(mut st) => {
-                client
-                    .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
-                    .await?;
-                tokio::io::copy_bidirectional(client, &mut st).await?;
-                Ok(())
+    // Only pool proxies are raced; built-ins are never a faster path.
+    let hedge = select_candidate(ctx, target_host, action, &|p: &Proxy| {
+        p.id != primary.id
+            && !p.is_direct()
+            && !p.is_reject()
+            && filter(p)
+            && !failed.contains(&p.id)
+    });
+    let Some(hedge) = hedge else {
+        let result = first.await;
+        return (primary.clone(), result);
+    };
+    println!(
+        "[Proxy] {} slow after {}ms, racing {} ({}:{})",
+        primary.id,
+        delay.as_millis(),
+        hedge.id,
+        hedge.host,
+        hedge.port
+    );
+    let second = open(hedge.clone());
+    tokio::pin!(second);
+
+    tokio::select! {
+        result = &mut first => match result {
+            Ok(tunnel) => (primary.clone(), Ok(tunnel)),
+            Err(e) => {
+                note_failure(&ctx.pool, &primary, &e);
+                failed.push(primary.id.clone());
+                let result = second.await;
+                (hedge.clone(), result)
+            }
+        },
+        result = &mut second => match result {
+            Ok(tunnel) => {
+                println!("[Proxy] {} won the race against {}", hedge.id, primary.id);
+                (hedge.clone(), Ok(tunnel))
             }
             Err(e) => {
-                client
-                    .write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
-                    .await?;
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::ConnectionRefused,
-                    e,
-                ))
+                note_failure(&ctx.pool, &hedge, &e);
+                failed.push(hedge.id.clone());
+                let result = first.await;
+                (primary.clone(), result)
             }
+        },
+    }
+}
+
+/// [`open_tunnel`] for client traffic: starts from an idle connection out of
+/// the warm pool when there is one, so only the request itself is on the
+/// client's clock.
+pub(crate) async fn open_client_tunnel(
+    ctx: &ClientContext,
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    if let Some(st) = ctx.warm.take(selected) {
+        match request(st, selected, target_host, target_port).await {
+            // The proxy dropped the idle connection, or wants a new one for
+            // its auth scheme: dial afresh.
+            Err(UpstreamError::Io(e)) if e.kind() != std::io::ErrorKind::TimedOut => {}
+            Err(UpstreamError::AuthRetry) => {}
+            result => return result,
         }
     }
+    open_tunnel(&ctx.pool, selected, target_host, target_port).await
+}
+
+/// [`dial_upstream`] without the log line or the warm pool; shared with the
+/// health checker, whose latency must include the connection setup.
+async fn open_tunnel(
+    pool: &ProxyPool,
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    // A proxy that hangs up on its `407` gets a second connection, which
+    // starts with the scheme it asked for.
+    match open_tunnel_once(pool, selected, target_host, target_port).await {
+        Err(UpstreamError::AuthRetry) => {
+            match open_tunnel_once(pool, selected, target_host, target_port).await {
+                Err(UpstreamError::AuthRetry) => Err(UpstreamError::AuthRejected),
+                result => result,
+            }
+        }
+        result => result,
+    }
+}
+
+async fn open_tunnel_once(
+    pool: &ProxyPool,
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    if selected.is_reject() {
+        return Err(UpstreamError::Rejected);
+    }
+    if selected.is_direct() {
+        return connect_direct(target_host, target_port).await;
+    }
+    if selected.is_chain() {
+        return crate::chain::open(pool, selected, target_host, target_port).await;
+    }
+    if selected.is_ssh() {
+        // Channels share one long-lived session instead of a new connection.
+        return Ok(Tunnel {
+            stream: crate::ssh::connect(selected, target_host, target_port).await?,
+            bound: None,
+        });
+    }
+
+    let st = connect_proxy(selected).await?;
+    negotiate(st, selected, target_host, target_port).await
+}
+
+/// Speaks `selected`'s proxy protocol over `st`, an open transport to that
+/// proxy, asking it for `target_host:target_port`.
+pub(crate) async fn negotiate(
+    mut st: UpstreamStream,
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    prepare(&mut st, selected).await?;
+    request(st, selected, target_host, target_port).await
+}
+
+/// The target-independent part of [`negotiate`]: the SOCKS5 greeting and
+/// authentication. Other protocols have nothing to say before the request.
+pub(crate) async fn prepare(
+    st: &mut UpstreamStream,
+    selected: &Proxy,
+) -> Result<(), UpstreamError> {
+    if selected.is_http() || selected.is_shadowsocks() || selected.is_socks4() {
+        return Ok(());
+    }
+    socks::handshake(st, selected.user.as_deref(), selected.pass.as_deref()).await
+}
+
+/// The rest of [`negotiate`], over a transport that has been through
+/// [`prepare`].
+async fn request(
+    mut st: UpstreamStream,
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    if selected.is_http() {
+        crate::http_connect::connect(st, selected, target_host, target_port).await
+    } else if selected.is_shadowsocks() {
+        Ok(Tunnel {
+            stream: crate::ss::connect(st, selected, target_host, target_port)?,
+            bound: None,
+        })
+    } else if selected.is_socks4() {
+        let remote_dns = selected.protocol == "socks4a";
+        let (host, port) = socks::connect_v4(
+            &mut st,
+            target_host,
+            target_port,
+            selected.user.as_deref(),
+            remote_dns,
+        )
+        .await?;
+        // Most SOCKS4 servers leave DSTIP/DSTPORT zeroed on CONNECT.
+        Ok(Tunnel {
+            stream: st,
+            bound: (port != 0).then_some((host, port)),
+        })
+    } else {
+        let (host, port) =
+            socks::request(&mut st, socks::CMD_CONNECT, target_host, target_port).await?;
+        Ok(Tunnel {
+            stream: st,
+            bound: Some((host, port)),
+        })
+    }
 }
 
 // ─── Health checker ─────────────────────────────────────────────────────────
 
-pub async fn check_single_proxy(pool: &ProxyPool, id: &str) {
+pub async fn check_single_proxy(pool: &ProxyPool, geoip: &GeoIp, id: &str) {
     let p = match pool.proxies.get(id).map(|r| r.value().clone()) {
         Some(p) => p,
         None => return,
     };
-    check_proxy_instance(pool, p).await;
+    check_proxy_instance(pool, geoip, p).await;
 }
 
-async fn check_proxy_instance(pool: &ProxyPool, mut p: Proxy) {
-    let proxy_addr = format!("{}:{}", p.host, p.port);
+async fn check_proxy_instance(pool: &ProxyPool, geoip: &GeoIp, p: Proxy) {
     let start = std::time::Instant::now();
 
-    let check_future = async {
-        if p.protocol == "http" || p.protocol == "https" {
-            let mut stream = tokio::net::TcpStream::connect(&proxy_addr).await?;
-            let target = "1.1.1.1:443";
-            let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
-            if let (Some(u), Some(pass)) = (p.user.clone(), p.pass.clone()) {
-                use base64::Engine;
-                let auth =
-                    base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", u, pass));
-                req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", auth));
-            }
-            req.push_str("\r\n");
-            stream.write_all(req.as_bytes()).await?;
-
-            let mut resp_buf = Vec::new();
+    // Same code path as real traffic, so every upstream protocol is covered.
+    let probe = async {
+        // A chain is probed end to end, like the hop it exits through.
+        let exit = if p.is_chain() {
+            crate::chain::exit_hop(pool, &p)
+        } else {
+            Some(p.clone())
+        };
+        if p.is_ssh() {
+            crate::ssh::check(&p).await
+        } else if exit.is_some_and(|e| e.is_shadowsocks()) {
+            // Shadowsocks has no connect reply; only a response proves the
+            // cipher, password and server all work.
+            let mut tunnel = open_tunnel(pool, &p, "1.1.1.1", 80).await?;
+            tunnel
+                .stream
+                .write_all(b"HEAD / HTTP/1.1\r\nHost: 1.1.1.1\r\nConnection: close\r\n\r\n")
+                .await?;
             let mut buf = [0u8; 1];
-            loop {
-                if stream.read_exact(&mut buf).await.is_err() {
-                    break;
-                }
-                resp_buf.push(buf[0]);
-                if resp_buf.ends_with(b"\r\n\r\n") {
-                    break;
-                }
-            }
-
-            if resp_buf.starts_with(b"HTTP/1.1 200") || resp_buf.starts_with(b"HTTP/1.0 200") {
-                Ok(())
-            } else {
-                Err("HTTP Connect failed".into())
+            if tunnel.stream.read(&mut buf).await? == 0 {
+                return Err(UpstreamError::Protocol(
+                    "Shadowsocks server closed the connection".to_string(),
+                ));
             }
+            Ok(())
         } else {
-            if let (Some(u), Some(pass)) = (p.user.clone(), p.pass.clone()) {
-                tokio_socks::tcp::Socks5Stream::connect_with_password(
-                    proxy_addr.as_str(),
-                    "1.1.1.1:443",
-                    &u,
-                    &pass,
-                )
-                .await
-                .map(|_| ())
-                .map_err(|e| e.into())
-            } else {
-                tokio_socks::tcp::Socks5Stream::connect(proxy_addr.as_str(), "1.1.1.1:443")
-                    .await
-                    .map(|_| ())
-                    .map_err(|e| e.into())
-            }
+            open_tunnel(pool, &p, "1.1.1.1", 443).await.map(|_| ())
         }
     };
-
-    let check: Result<(), Box<dyn std::error::Error + Send + Sync>> =
-        match tokio::time::timeout(std::time::Duration::from_secs(5), check_future).await {
-            Ok(res) => res,
-            Err(_) => Err("Health check timed out".into()),
-        };
+    let check = tokio::time::timeout(std::time::Duration::from_secs(5), probe).await;
 
     let latency = start.elapsed().as_millis() as u64;
-    if check.is_ok() {
-        p.is_alive = true;
-        p.latency_ms = Some(latency);
-    } else {
-        p.is_alive = false;
-        p.latency_ms = None;
+    let alive = matches!(check, Ok(Ok(_)));
+    if alive {
+        pool.clear_penalty(&p.id);
+    }
+    // Only the health fields are written back: the proxy may have been
+    // edited or removed while the check ran.
+    match pool.proxies.get_mut(&p.id) {
+        Some(mut current) => {
+            current.is_alive = alive;
+            current.latency_ms = alive.then_some(latency);
+        }
+        None => return,
+    }
+    if alive && geoip.is_enabled() {
+        // Beside the health pass, so a slow trace doesn't hold up the
+        // checks of the other proxies.
+        let pool = pool.clone();
+        let geoip = geoip.clone();
+        tauri::async_runtime::spawn(async move { locate(&pool, &geoip, p).await });
+    }
+}
+
+/// Asks a trace endpoint through `p` which address its traffic leaves from.
+async fn discover_exit_ip(pool: &ProxyPool, p: &Proxy) -> Option<String> {
+    let fetch = async {
+        let mut tunnel = open_tunnel(pool, p, "1.1.1.1", 80).await?;
+        tunnel
+            .stream
+            .write_all(b"GET /cdn-cgi/trace HTTP/1.1\r\nHost: 1.1.1.1\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut body = Vec::new();
+        (&mut tunnel.stream)
+            .take(16 * 1024)
+            .read_to_end(&mut body)
+            .await?;
+        Ok::<_, UpstreamError>(body)
+    };
+    let body = tokio::time::timeout(std::time::Duration::from_secs(5), fetch)
+        .await
+        .ok()?
+        .ok()?;
+    String::from_utf8_lossy(&body)
+        .lines()
+        .find_map(|line| line.strip_prefix("ip="))
+        .and_then(|ip| ip.trim().parse::<std::net::IpAddr>().ok())
+        .map(|ip| ip.to_string())
+}
+
+/// Re-learns `p`'s exit address, which rotating and residential proxies
+/// change over time, and refreshes its GeoIP data. When the exit can't be
+/// learned the last known one is kept, or the proxy is located by its host's
+/// address.
+async fn locate(pool: &ProxyPool, geoip: &GeoIp, mut p: Proxy) {
+    if let Some(exit_ip) = discover_exit_ip(pool, &p).await {
+        p.exit_ip = Some(exit_ip);
+    }
+    if p.exit_ip.is_none() && !p.is_chain() && p.host.parse::<std::net::IpAddr>().is_err() {
+        let resolve = tokio::net::lookup_host((p.host.as_str(), p.port));
+        if let Ok(Ok(mut addrs)) = tokio::time::timeout(Duration::from_secs(5), resolve).await {
+            p.host_ip = addrs.next().map(|a| a.ip().to_string());
+        }
+    }
+    geoip.enrich(&mut p);
+    if let Some(mut current) = pool.proxies.get_mut(&p.id) {
+        current.exit_ip = p.exit_ip;
+        current.host_ip = p.host_ip;
+        current.geo = p.geo;
     }
-    pool.add(p);
 }
 
-pub async fn check_all_proxies(pool: &ProxyPool) {
+pub async fn check_all_proxies(pool: &ProxyPool, geoip: &GeoIp) {
     let proxies = pool.get_all();
     for p in proxies {
-        check_proxy_instance(pool, p).await;
+        check_proxy_instance(pool, geoip, p).await;
     }
 }
 
-pub fn start_health_checker(pool: ProxyPool) {
+pub fn start_health_checker(pool: ProxyPool, geoip: GeoIp) {
     tauri::async_runtime::spawn(async move {
         loop {
-            check_all_proxies(&pool).await;
+            check_all_proxies(&pool, &geoip).await;
             tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
         }
     });
@@ -0,0 +1,178 @@
+use crate::socks;
+use thiserror::Error;
+
+/// Why a connection through an upstream proxy could not be established.
+#[derive(Error, Debug)]
+pub enum UpstreamError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Cannot reach proxy: {0}")]
+    ProxyUnreachable(std::io::Error),
+    #[error("TLS handshake with proxy failed: {0}")]
+    Tls(String),
+    #[error("Proxy rejected our credentials")]
+    AuthRejected,
+    #[error("Proxy closed the connection while asking for credentials")]
+    AuthRetry,
+    #[error("Connection refused by policy")]
+    Rejected,
+    #[error("Cannot reach target directly: {0}")]
+    TargetUnreachable(std::io::Error),
+    #[error("Proxy protocol error: {0}")]
+    Protocol(String),
+    #[error("Upstream SOCKS reply 0x{0:02x}")]
+    SocksReply(u8),
+    #[error("Upstream SOCKS4 request rejected (0x{0:02x})")]
+    Socks4Rejected(u8),
+    #[error("HTTP proxy requires authentication (407)")]
+    HttpAuthRequired,
+    #[error("HTTP proxy refused the target (403)")]
+    HttpForbidden,
+    #[error("HTTP proxy could not reach the target (502)")]
+    HttpBadGateway,
+    #[error("HTTP proxy is unavailable (503)")]
+    HttpUnavailable,
+    #[error("HTTP proxy timed out reaching the target (504)")]
+    HttpGatewayTimeout,
+    #[error("HTTP CONNECT failed with status {0}")]
+    HttpStatus(u16),
+}
+
+impl UpstreamError {
+    /// The error for a non-2xx answer to `CONNECT`.
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            407 => UpstreamError::HttpAuthRequired,
+            403 => UpstreamError::HttpForbidden,
+            502 => UpstreamError::HttpBadGateway,
+            503 => UpstreamError::HttpUnavailable,
+            504 => UpstreamError::HttpGatewayTimeout,
+            other => UpstreamError::HttpStatus(other),
+        }
+    }
+
+    /// Whether the upstream itself is to blame, as opposed to the target
+    /// being unreachable through it or a policy refusing the connection.
+    /// SOCKS4 0x5C/0x5D mean the proxy wants an identd we don't run, so
+    /// every request through it fails the same way.
+    pub fn is_proxy_fault(&self) -> bool {
+        !matches!(
+            self,
+            UpstreamError::Rejected
+                | UpstreamError::TargetUnreachable(_)
+                | UpstreamError::SocksReply(0x02..=0x08)
+                | UpstreamError::Socks4Rejected(0x5B)
+                | UpstreamError::HttpForbidden
+                | UpstreamError::HttpBadGateway
+                | UpstreamError::HttpGatewayTimeout
+        )
+    }
+
+    /// The RFC 1928 reply code that best describes this failure to a SOCKS5
+    /// client. A dead or misconfigured proxy is a general failure; only
+    /// errors about the target itself get the more specific codes.
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            UpstreamError::SocksReply(code @ 0x01..=0x08) => *code,
+            UpstreamError::SocksReply(_) => socks::REPLY_GENERAL_FAILURE,
+            UpstreamError::Rejected => socks::REPLY_NOT_ALLOWED,
+            UpstreamError::TargetUnreachable(e) => match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => socks::REPLY_CONNECTION_REFUSED,
+                std::io::ErrorKind::NetworkUnreachable => socks::REPLY_NETWORK_UNREACHABLE,
+                std::io::ErrorKind::TimedOut => socks::REPLY_TTL_EXPIRED,
+                _ => socks::REPLY_HOST_UNREACHABLE,
+            },
+            UpstreamError::HttpForbidden => socks::REPLY_NOT_ALLOWED,
+            UpstreamError::HttpBadGateway => socks::REPLY_HOST_UNREACHABLE,
+            UpstreamError::HttpUnavailable => socks::REPLY_NETWORK_UNREACHABLE,
+            UpstreamError::HttpGatewayTimeout => socks::REPLY_TTL_EXPIRED,
+            UpstreamError::Io(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                socks::REPLY_TTL_EXPIRED
+            }
+            _ => socks::REPLY_GENERAL_FAILURE,
+        }
+    }
+}
+
+impl From<UpstreamError> for std::io::Error {
+    fn from(e: UpstreamError) -> Self {
+        match e {
+            UpstreamError::Io(e) => e,
+            other => std::io::Error::other(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn relays_upstream_socks_replies() {
+        for code in 0x01..=0x08 {
+            assert_eq!(UpstreamError::SocksReply(code).reply_code(), code);
+        }
+        assert_eq!(
+            UpstreamError::SocksReply(0x42).reply_code(),
+            socks::REPLY_GENERAL_FAILURE
+        );
+    }
+
+    #[test]
+    fn maps_direct_failures_by_kind() {
+        let direct = |kind| UpstreamError::TargetUnreachable(Error::from(kind)).reply_code();
+        assert_eq!(
+            direct(ErrorKind::ConnectionRefused),
+            socks::REPLY_CONNECTION_REFUSED
+        );
+        assert_eq!(
+            direct(ErrorKind::NetworkUnreachable),
+            socks::REPLY_NETWORK_UNREACHABLE
+        );
+        assert_eq!(direct(ErrorKind::TimedOut), socks::REPLY_TTL_EXPIRED);
+        assert_eq!(direct(ErrorKind::Other), socks::REPLY_HOST_UNREACHABLE);
+    }
+
+    #[test]
+    fn maps_http_statuses() {
+        let code = |status| UpstreamError::from_http_status(status).reply_code();
+        assert_eq!(code(403), socks::REPLY_NOT_ALLOWED);
+        assert_eq!(code(502), socks::REPLY_HOST_UNREACHABLE);
+        assert_eq!(code(503), socks::REPLY_NETWORK_UNREACHABLE);
+        assert_eq!(code(504), socks::REPLY_TTL_EXPIRED);
+        assert_eq!(code(407), socks::REPLY_GENERAL_FAILURE);
+        assert_eq!(code(500), socks::REPLY_GENERAL_FAILURE);
+    }
+
+    #[test]
+    fn blames_proxy_for_identd_rejections() {
+        assert!(!UpstreamError::Socks4Rejected(0x5B).is_proxy_fault());
+        assert!(UpstreamError::Socks4Rejected(0x5C).is_proxy_fault());
+        assert!(UpstreamError::Socks4Rejected(0x5D).is_proxy_fault());
+        assert!(!UpstreamError::SocksReply(0x05).is_proxy_fault());
+        assert!(UpstreamError::SocksReply(0x01).is_proxy_fault());
+        assert!(UpstreamError::HttpAuthRequired.is_proxy_fault());
+    }
+
+    #[test]
+    fn proxy_trouble_is_a_general_failure() {
+        assert_eq!(
+            UpstreamError::Rejected.reply_code(),
+            socks::REPLY_NOT_ALLOWED
+        );
+        assert_eq!(
+            UpstreamError::Io(Error::from(ErrorKind::TimedOut)).reply_code(),
+            socks::REPLY_TTL_EXPIRED
+        );
+        for e in [
+            UpstreamError::Io(Error::from(ErrorKind::ConnectionReset)),
+            UpstreamError::ProxyUnreachable(Error::from(ErrorKind::ConnectionRefused)),
+            UpstreamError::AuthRejected,
+            UpstreamError::Tls("bad certificate".to_string()),
+            UpstreamError::Protocol("garbage".to_string()),
+        ] {
+            assert_eq!(e.reply_code(), socks::REPLY_GENERAL_FAILURE, "{}", e);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::state::{GeoInfo, Proxy, ProxyPool};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+// Offline GeoIP: proxy addresses are looked up in local MaxMind-format
+// (`.mmdb`) databases, e.g. GeoLite2-City plus GeoLite2-ASN. Every configured
+// database is consulted and their answers merged, so country, city and ASN
+// can come from different files. Lookups never touch the network; the exit
+// address itself is learned by the health checker.
+
+const GEOIP_FILE: &str = "geoip.json";
+
+/// What is persisted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GeoIpFile {
+    #[serde(default)]
+    databases: Vec<String>,
+}
+
+/// One configured database as reported to the frontend.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseStatus {
+    pub path: String,
+    /// E.g. `GeoLite2-City`; `None` when the file could not be opened.
+    pub database_type: Option<String>,
+    /// Build time, in seconds since the Unix epoch.
+    pub build_epoch: Option<u64>,
+    pub error: Option<String>,
+}
+
+struct Database {
+    path: String,
+    reader: Result<Reader<Vec<u8>>, String>,
+}
+
+fn open(paths: &[String]) -> Vec<Database> {
+    paths
+        .iter()
+        .map(|path| {
+            let reader = Reader::open_readfile(path).map_err(|e| e.to_string());
+            if let Err(e) = &reader {
+                eprintln!("[GeoIP] Cannot open {}: {}", path, e);
+            }
+            Database {
+                path: path.clone(),
+                reader,
+            }
+        })
+        .collect()
+}
+
+/// The configured databases. Clones share them.
+#[derive(Clone, Default)]
+pub struct GeoIp {
+    databases: Arc<RwLock<Vec<Database>>>,
+    storage: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl GeoIp {
+    /// Loads the persisted database list from `dir` and saves there from now
+    /// on.
+    pub fn load(&self, dir: PathBuf) {
+        let path = dir.join(GEOIP_FILE);
+        match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<GeoIpFile>(&bytes) {
+                Ok(file) => *self.databases.write().unwrap() = open(&file.databases),
+                Err(e) => eprintln!("[GeoIP] Ignoring corrupt {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("[GeoIP] Cannot read {}: {}", path.display(), e),
+        }
+        *self.storage.lock().unwrap() = Some(path);
+    }
+
+    fn save(&self) {
+        let Some(path) = self.storage.lock().unwrap().clone() else {
+            return;
+        };
+        let file = GeoIpFile {
+            databases: self.paths(),
+        };
+        let json = serde_json::to_vec_pretty(&file);
+        let result = json.map_err(std::io::Error::other).and_then(|bytes| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, bytes)
+        });
+        if let Err(e) = result {
+            eprintln!("[GeoIP] Cannot write {}: {}", path.display(), e);
+        }
+    }
+
+    fn paths(&self) -> Vec<String> {
+        let databases = self.databases.read().unwrap();
+        databases.iter().map(|d| d.path.clone()).collect()
+    }
+
+    pub fn status(&self) -> Vec<DatabaseStatus> {
+        let databases = self.databases.read().unwrap();
+        databases
+            .iter()
+            .map(|d| match &d.reader {
+                Ok(reader) => DatabaseStatus {
+                    path: d.path.clone(),
+                    database_type: Some(reader.metadata.database_type.clone()),
+                    build_epoch: Some(reader.metadata.build_epoch),
+                    error: None,
+                },
+                Err(e) => DatabaseStatus {
+                    path: d.path.clone(),
+                    database_type: None,
+                    build_epoch: None,
+                    error: Some(e.clone()),
+                },
+            })
+            .collect()
+    }
+
+    /// Replaces the database list. Files that can't be opened are kept in
+    /// the list, with their error, so a later [`GeoIp::reload`] can pick
+    /// them up.
+    pub fn set_databases(&self, paths: Vec<String>) {
+        let databases = open(&paths);
+        *self.databases.write().unwrap() = databases;
+        self.save();
+    }
+
+    /// Re-reads every configured database from disk, e.g. after an update.
+    pub fn reload(&self) {
+        let databases = open(&self.paths());
+        *self.databases.write().unwrap() = databases;
+    }
+
+    /// Whether any database is loaded.
+    pub fn is_enabled(&self) -> bool {
+        let databases = self.databases.read().unwrap();
+        databases.iter().any(|d| d.reader.is_ok())
+    }
+
+    /// Looks `ip` up in every loaded database. `None` when none knows it.
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let databases = self.databases.read().unwrap();
+        let mut geo = GeoInfo::default();
+        for reader in databases.iter().filter_map(|d| d.reader.as_ref().ok()) {
+            // Each record decodes as either model; fields a database doesn't
+            // have stay empty.
+            match reader.lookup::<geoip2::City>(ip) {
+                Ok(city) => {
+                    let country = city.country.or(city.registered_country);
+                    geo.country = geo
+                        .country
+                        .or_else(|| country?.iso_code.map(str::to_string));
+                    geo.city = geo.city.or_else(|| {
+                        let names = city.city?.names?;
+                        names.get("en").map(|n| n.to_string())
+                    });
+                }
+                Err(MaxMindDBError::AddressNotFoundError(_)) => continue,
+                Err(e) => eprintln!("[GeoIP] Lookup of {} failed: {}", ip, e),
+            }
+            if let Ok(asn) = reader.lookup::<geoip2::Asn>(ip) {
+                geo.asn = geo.asn.or(asn.autonomous_system_number);
+                geo.as_org = geo
+                    .as_org
+                    .or_else(|| asn.autonomous_system_organization.map(str::to_string));
+            }
+        }
+        (geo != GeoInfo::default()).then_some(geo)
+    }
+
+    /// Refreshes `p.geo` from the databases. Left alone when `p` has no
+    /// known address yet.
+    pub fn enrich(&self, p: &mut Proxy) {
+        if let Some(ip) = locate_by(p) {
+            p.geo = self.lookup(ip);
+        }
+    }
+
+    /// Refreshes the GeoIP data of every proxy, after the databases changed.
+    pub fn enrich_all(&self, pool: &ProxyPool) {
+        for mut p in pool.proxies.iter_mut() {
+            self.enrich(&mut p);
+        }
+    }
+}
+
+/// The address to locate `p` by: its exit address when known, else its
+/// host's, as an IP literal or as last resolved.
+fn locate_by(p: &Proxy) -> Option<IpAddr> {
+    [
+        p.exit_ip.as_deref(),
+        Some(p.host.as_str()),
+        p.host_ip.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .find_map(|ip| ip.parse().ok())
+}
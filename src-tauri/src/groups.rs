@@ -0,0 +1,154 @@
+use crate::state::{Proxy, ProxyPool, RotationMode};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+
+// Proxy groups: named sets of pool proxies defined by a tag filter, each with
+// its own rotation mode and round-robin position. Listeners and routing rules
+// can point at a group instead of the whole pool. Membership is evaluated per
+// connection, so tagging a proxy moves it into matching groups right away.
+// Groups are persisted next to the rules that reference them.
+
+const GROUPS_FILE: &str = "groups.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupConfig {
+    pub id: String,
+    pub name: String,
+    /// A proxy is a member when it carries every one of these tags.
+    pub tags: Vec<String>,
+    pub rotation_mode: RotationMode,
+}
+
+impl GroupConfig {
+    pub fn contains(&self, p: &Proxy) -> bool {
+        self.tags.iter().all(|t| p.has_tag(t))
+    }
+}
+
+/// A group as reported to the frontend.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupInfo {
+    #[serde(flatten)]
+    pub config: GroupConfig,
+    /// Ids of the pool proxies currently in the group, alive or not.
+    pub members: Vec<String>,
+}
+
+/// Runtime state of one group. Clones share the round-robin position.
+#[derive(Clone)]
+pub struct Group {
+    pub config: GroupConfig,
+    pub round_robin_idx: Arc<AtomicUsize>,
+}
+
+#[derive(Clone, Default)]
+pub struct Groups {
+    groups: Arc<DashMap<String, Group>>,
+    storage: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl Groups {
+    /// Loads the persisted groups from `dir` and saves there from now on.
+    pub fn load(&self, dir: PathBuf) {
+        let path = dir.join(GROUPS_FILE);
+        match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<Vec<GroupConfig>>(&bytes) {
+                Ok(configs) => {
+                    self.groups.clear();
+                    for config in configs {
+                        self.insert(config);
+                    }
+                }
+                Err(e) => eprintln!("[Groups] Ignoring corrupt {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("[Groups] Cannot read {}: {}", path.display(), e),
+        }
+        *self.storage.lock().unwrap() = Some(path);
+    }
+
+    fn save(&self) {
+        let Some(path) = self.storage.lock().unwrap().clone() else {
+            return;
+        };
+        let configs: Vec<GroupConfig> = self.list().into_iter().map(|g| g.config).collect();
+        let json = serde_json::to_vec_pretty(&configs);
+        let result = json.map_err(std::io::Error::other).and_then(|bytes| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, bytes)
+        });
+        if let Err(e) = result {
+            eprintln!("[Groups] Cannot write {}: {}", path.display(), e);
+        }
+    }
+
+    fn insert(&self, config: GroupConfig) {
+        self.groups.insert(
+            config.id.clone(),
+            Group {
+                config,
+                round_robin_idx: Arc::new(AtomicUsize::new(0)),
+            },
+        );
+    }
+
+    pub fn get(&self, id: &str) -> Option<Group> {
+        self.groups.get(id).map(|g| g.value().clone())
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.groups.contains_key(id)
+    }
+
+    /// All groups, ordered by name.
+    pub fn list(&self) -> Vec<Group> {
+        let mut groups: Vec<Group> = self.groups.iter().map(|g| g.value().clone()).collect();
+        groups.sort_by(|a, b| a.config.name.cmp(&b.config.name));
+        groups
+    }
+
+    /// All groups with their current members.
+    pub fn list_info(&self, pool: &ProxyPool) -> Vec<GroupInfo> {
+        let mut proxies = pool.get_all();
+        proxies.sort_by(|a, b| a.id.cmp(&b.id));
+        self.list()
+            .into_iter()
+            .map(|g| GroupInfo {
+                members: proxies
+                    .iter()
+                    .filter(|p| g.config.contains(p))
+                    .map(|p| p.id.clone())
+                    .collect(),
+                config: g.config,
+            })
+            .collect()
+    }
+
+    pub fn add(&self, config: GroupConfig) {
+        self.insert(config);
+        self.save();
+    }
+
+    /// Replaces a group's settings, keeping its round-robin position.
+    pub fn update(&self, config: GroupConfig) -> bool {
+        match self.groups.get_mut(&config.id) {
+            Some(mut g) => g.config = config,
+            None => return false,
+        }
+        self.save();
+        true
+    }
+
+    pub fn remove(&self, id: &str) -> bool {
+        let removed = self.groups.remove(id).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+}
@@ -0,0 +1,795 @@
+use crate::engine::join_host_port;
+use crate::error::UpstreamError;
+use crate::http_proxy::HttpResponse;
+use crate::state::Proxy;
+use base64::Engine;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+// Challenge-response authentication for HTTP upstreams. A `407` lists the
+// schemes the proxy accepts in `Proxy-Authenticate`; we answer the strongest
+// one we support (Digest, then NTLM, then Basic) and remember the choice per
+// proxy, so later connections authenticate up front instead of paying for an
+// extra round trip.
+
+/// Upper bound on `407` round trips on one connection; a proxy that keeps
+/// marking our Digest nonce stale is not going to let us in.
+const MAX_ROUNDS: usize = 4;
+
+/// The scheme a proxy asked for last.
+#[derive(Debug, Clone)]
+enum Scheme {
+    Basic,
+    Digest(DigestChallenge),
+    /// NTLM authenticates a connection, not a request.
+    Ntlm,
+}
+
+fn schemes() -> &'static DashMap<String, Scheme> {
+    static SCHEMES: OnceLock<DashMap<String, Scheme>> = OnceLock::new();
+    SCHEMES.get_or_init(DashMap::new)
+}
+
+fn scheme_key(p: &Proxy) -> String {
+    format!(
+        "{}@{}",
+        p.user.as_deref().unwrap_or(""),
+        join_host_port(&p.host, p.port)
+    )
+}
+
+/// Where an authentication exchange stands after sending `header`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Step {
+    Basic,
+    Digest,
+    NtlmNegotiate,
+    NtlmAuthenticate,
+}
+
+/// A `Proxy-Authorization` value and what it answered.
+#[derive(Debug, Clone)]
+pub(crate) struct Authorization {
+    pub header: String,
+    pub step: Step,
+    round: usize,
+}
+
+fn credentials(p: &Proxy) -> Option<(&str, &str)> {
+    Some((p.user.as_deref()?, p.pass.as_deref()?))
+}
+
+/// Whether the proxy uses a scheme that binds to one connection, so
+/// requests can't be forwarded to it without a `CONNECT` tunnel.
+pub(crate) fn is_connection_bound(p: &Proxy) -> bool {
+    matches!(schemes().get(&scheme_key(p)).as_deref(), Some(Scheme::Ntlm))
+}
+
+/// Credentials to send with the first request: whatever the proxy asked for
+/// last time, `Basic` if it never asked.
+pub(crate) fn preemptive(p: &Proxy, method: &str, uri: &str) -> Option<Authorization> {
+    let (user, pass) = credentials(p)?;
+    let (header, step) = match schemes().get_mut(&scheme_key(p)).as_deref_mut() {
+        None | Some(Scheme::Basic) => (basic(user, pass), Step::Basic),
+        Some(Scheme::Digest(challenge)) => {
+            (digest(challenge, user, pass, method, uri), Step::Digest)
+        }
+        Some(Scheme::Ntlm) => (ntlm_negotiate(), Step::NtlmNegotiate),
+    };
+    Some(Authorization {
+        header,
+        step,
+        round: 0,
+    })
+}
+
+/// Answers a `407`. `sent` is what the rejected request carried. Fails with
+/// `AuthRejected` once the proxy turns down credentials we computed for the
+/// exact challenge it sent.
+pub(crate) fn respond(
+    p: &Proxy,
+    method: &str,
+    uri: &str,
+    response: &HttpResponse,
+    sent: Option<&Authorization>,
+) -> Result<Authorization, UpstreamError> {
+    let Some((user, pass)) = credentials(p) else {
+        return Err(UpstreamError::HttpAuthRequired);
+    };
+    let round = sent.map_or(0, |a| a.round + 1);
+    if round >= MAX_ROUNDS {
+        return Err(UpstreamError::AuthRejected);
+    }
+    let sent_step = sent.map(|a| a.step);
+
+    let challenges: Vec<Challenge> = response
+        .headers_named("Proxy-Authenticate")
+        .flat_map(parse_challenges)
+        .collect();
+
+    // Second leg of NTLM: the proxy's challenge to our negotiate message.
+    if sent_step == Some(Step::NtlmNegotiate) {
+        if let Some(token) = challenges
+            .iter()
+            .find(|c| c.scheme == "ntlm")
+            .and_then(|c| c.token.as_deref())
+        {
+            let header = ntlm_authenticate(token, user, pass)
+                .ok_or_else(|| UpstreamError::Protocol("Invalid NTLM challenge".to_string()))?;
+            return Ok(Authorization {
+                header,
+                step: Step::NtlmAuthenticate,
+                round,
+            });
+        }
+    }
+
+    let (scheme, header, step) = match strongest(&challenges) {
+        Some(Scheme::Digest(mut challenge)) => {
+            if sent_step == Some(Step::Digest) && !challenge.stale {
+                return Err(UpstreamError::AuthRejected);
+            }
+            let header = digest(&mut challenge, user, pass, method, uri);
+            (Scheme::Digest(challenge), header, Step::Digest)
+        }
+        Some(Scheme::Ntlm) => {
+            if matches!(
+                sent_step,
+                Some(Step::NtlmNegotiate | Step::NtlmAuthenticate)
+            ) {
+                return Err(UpstreamError::AuthRejected);
+            }
+            (Scheme::Ntlm, ntlm_negotiate(), Step::NtlmNegotiate)
+        }
+        Some(Scheme::Basic) => {
+            if sent_step == Some(Step::Basic) {
+                return Err(UpstreamError::AuthRejected);
+            }
+            (Scheme::Basic, basic(user, pass), Step::Basic)
+        }
+        None => return Err(UpstreamError::HttpAuthRequired),
+    };
+    schemes().insert(scheme_key(p), scheme);
+    Ok(Authorization {
+        header,
+        step,
+        round,
+    })
+}
+
+/// The best challenge we can answer: Digest (SHA-256 over MD5), then NTLM,
+/// then Basic.
+fn strongest(challenges: &[Challenge]) -> Option<Scheme> {
+    let digest = challenges
+        .iter()
+        .filter(|c| c.scheme == "digest")
+        .filter_map(DigestChallenge::parse)
+        .max_by_key(|d| d.sha256);
+    if let Some(d) = digest {
+        return Some(Scheme::Digest(d));
+    }
+    if challenges.iter().any(|c| c.scheme == "ntlm") {
+        return Some(Scheme::Ntlm);
+    }
+    if challenges.iter().any(|c| c.scheme == "basic") {
+        return Some(Scheme::Basic);
+    }
+    None
+}
+
+// ─── Challenge parsing ──────────────────────────────────────────────────────
+
+/// One challenge from a `Proxy-Authenticate` header (RFC 7235 §2.1).
+#[derive(Debug)]
+struct Challenge {
+    /// Lowercased scheme name.
+    scheme: String,
+    /// A bare `token68`, as NTLM uses.
+    token: Option<String>,
+    /// Parameters with lowercased names and unquoted values.
+    params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Splits on commas outside quoted strings.
+fn split_list(value: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(value[start..].trim());
+    items.retain(|s| !s.is_empty());
+    items
+}
+
+/// `name=value` as opposed to a `token68` such as `TlRMTVNTUAAC...==`.
+fn split_param(item: &str) -> Option<(String, String)> {
+    let (name, value) = item.split_once('=')?;
+    let name = name.trim();
+    let value = value.trim();
+    if name.is_empty() || name.contains(' ') || value.is_empty() || value.starts_with('=') {
+        return None;
+    }
+    let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => {
+            let mut out = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                out.push(if c == '\\' {
+                    chars.next().unwrap_or(c)
+                } else {
+                    c
+                });
+            }
+            out
+        }
+        None => value.to_string(),
+    };
+    Some((name.to_ascii_lowercase(), value))
+}
+
+/// Parses one header value, which may hold several challenges.
+fn parse_challenges(value: &str) -> Vec<Challenge> {
+    let mut challenges: Vec<Challenge> = Vec::new();
+    for item in split_list(value) {
+        // A scheme name is followed by a space (or nothing) before any `=`.
+        let starts_challenge = match (item.find(' '), item.find('=')) {
+            (Some(space), Some(eq)) => space < eq,
+            (_, None) => true,
+            (None, Some(_)) => false,
+        };
+        if starts_challenge {
+            let (scheme, rest) = item.split_once(' ').unwrap_or((item, ""));
+            let rest = rest.trim();
+            let mut challenge = Challenge {
+                scheme: scheme.to_ascii_lowercase(),
+                token: None,
+                params: Vec::new(),
+            };
+            if !rest.is_empty() {
+                match split_param(rest) {
+                    Some(param) => challenge.params.push(param),
+                    None => challenge.token = Some(rest.to_string()),
+                }
+            }
+            challenges.push(challenge);
+        } else if let (Some(current), Some(param)) = (challenges.last_mut(), split_param(item)) {
+            current.params.push(param);
+        }
+    }
+    challenges
+}
+
+// ─── Basic ──────────────────────────────────────────────────────────────────
+
+fn basic(user: &str, pass: &str) -> String {
+    let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass));
+    format!("Basic {}", token)
+}
+
+// ─── Digest (RFC 7616) ──────────────────────────────────────────────────────
+
+#[derive(Debug, Clone)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    /// The `algorithm` parameter as sent, if any.
+    algorithm: Option<String>,
+    sha256: bool,
+    session: bool,
+    /// Whether `qop=auth` was offered; without it the RFC 2069 form is used.
+    qop_auth: bool,
+    stale: bool,
+    nonce_count: u32,
+}
+
+impl DigestChallenge {
+    fn parse(c: &Challenge) -> Option<Self> {
+        let algorithm = c.param("algorithm").map(str::to_string);
+        let (sha256, session) = match algorithm.as_deref().map(str::to_ascii_uppercase) {
+            None => (false, false),
+            Some(a) => match a.as_str() {
+                "MD5" => (false, false),
+                "MD5-SESS" => (false, true),
+                "SHA-256" => (true, false),
+                "SHA-256-SESS" => (true, true),
+                _ => return None,
+            },
+        };
+        let qop_auth = c
+            .param("qop")
+            .is_some_and(|q| q.split(',').any(|q| q.trim().eq_ignore_ascii_case("auth")));
+        // `auth-int` alone would need the body hash, which a CONNECT lacks.
+        if c.param("qop").is_some() && !qop_auth {
+            return None;
+        }
+        Some(Self {
+            realm: c.param("realm").unwrap_or("").to_string(),
+            nonce: c.param("nonce")?.to_string(),
+            opaque: c.param("opaque").map(str::to_string),
+            algorithm,
+            sha256,
+            session,
+            qop_auth,
+            stale: c
+                .param("stale")
+                .is_some_and(|s| s.eq_ignore_ascii_case("true")),
+            nonce_count: 0,
+        })
+    }
+
+    fn hash(&self, data: &str) -> String {
+        if self.sha256 {
+            hex(&Sha256::digest(data.as_bytes()))
+        } else {
+            hex(&Md5::digest(data.as_bytes()))
+        }
+    }
+}
+
+/// Answers `c`. Nonces are reused across connections, each use with the
+/// next nonce count.
+fn digest(c: &mut DigestChallenge, user: &str, pass: &str, method: &str, uri: &str) -> String {
+    let cnonce = hex(uuid::Uuid::new_v4().as_bytes());
+    digest_with_cnonce(c, user, pass, method, uri, &cnonce)
+}
+
+fn digest_with_cnonce(
+    c: &mut DigestChallenge,
+    user: &str,
+    pass: &str,
+    method: &str,
+    uri: &str,
+    cnonce: &str,
+) -> String {
+    c.nonce_count += 1;
+    let nc = format!("{:08x}", c.nonce_count);
+
+    let mut ha1 = c.hash(&format!("{}:{}:{}", user, c.realm, pass));
+    if c.session {
+        ha1 = c.hash(&format!("{}:{}:{}", ha1, c.nonce, cnonce));
+    }
+    let ha2 = c.hash(&format!("{}:{}", method, uri));
+    let response = if c.qop_auth {
+        c.hash(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            ha1, c.nonce, nc, cnonce, ha2
+        ))
+    } else {
+        c.hash(&format!("{}:{}:{}", ha1, c.nonce, ha2))
+    };
+
+    let mut header = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+        quote(user),
+        quote(&c.realm),
+        quote(&c.nonce),
+        quote(uri),
+        response
+    );
+    if let Some(algorithm) = &c.algorithm {
+        header.push_str(&format!(", algorithm={}", algorithm));
+    }
+    if c.qop_auth {
+        header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+    }
+    if let Some(opaque) = &c.opaque {
+        header.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+    }
+    header
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ─── NTLM (MS-NLMP, NTLMv2 only) ────────────────────────────────────────────
+
+const NTLM_SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NTLM_FLAGS: u32 = 0x0000_0001 // NEGOTIATE_UNICODE
+    | 0x0000_0004 // REQUEST_TARGET
+    | 0x0000_0200 // NEGOTIATE_NTLM
+    | 0x0000_8000 // NEGOTIATE_ALWAYS_SIGN
+    | 0x0008_0000 // NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | 0x0080_0000 // NEGOTIATE_TARGET_INFO
+    | 0x2000_0000 // NEGOTIATE_128
+    | 0x8000_0000; // NEGOTIATE_56
+
+/// `MsvAvTimestamp` in the target info AV pairs.
+const MSV_AV_TIMESTAMP: u16 = 7;
+
+/// Seconds between 1601-01-01 (the FILETIME epoch) and 1970-01-01.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+type HmacMd5 = Hmac<Md5>;
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <HmacMd5 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn ntlm_negotiate() -> String {
+    let mut msg = Vec::with_capacity(32);
+    msg.extend_from_slice(NTLM_SIGNATURE);
+    msg.extend_from_slice(&1u32.to_le_bytes());
+    msg.extend_from_slice(&NTLM_FLAGS.to_le_bytes());
+    // Empty domain and workstation security buffers.
+    msg.extend_from_slice(&[0u8; 16]);
+    format!(
+        "NTLM {}",
+        base64::engine::general_purpose::STANDARD.encode(msg)
+    )
+}
+
+/// A security buffer (`len`, `max_len`, `offset`) inside an NTLM message.
+fn security_buffer(msg: &[u8], at: usize) -> Option<&[u8]> {
+    let field = msg.get(at..at + 8)?;
+    let len = u16::from_le_bytes([field[0], field[1]]) as usize;
+    let offset = u32::from_le_bytes([field[4], field[5], field[6], field[7]]) as usize;
+    msg.get(offset..offset.checked_add(len)?)
+}
+
+/// The server's timestamp from the target info, if it sent one.
+fn av_timestamp(mut target_info: &[u8]) -> Option<[u8; 8]> {
+    while target_info.len() >= 4 {
+        let id = u16::from_le_bytes([target_info[0], target_info[1]]);
+        let len = u16::from_le_bytes([target_info[2], target_info[3]]) as usize;
+        let value = target_info.get(4..4 + len)?;
+        if id == MSV_AV_TIMESTAMP && len == 8 {
+            return value.try_into().ok();
+        }
+        target_info = &target_info[4 + len..];
+    }
+    None
+}
+
+/// `NTOWFv2` of MS-NLMP §3.3.2.
+fn ntowf_v2(user: &str, domain: &str, pass: &str) -> [u8; 16] {
+    let nt_hash: [u8; 16] = md4::Md4::digest(utf16le(pass)).into();
+    hmac_md5(
+        &nt_hash,
+        &[&utf16le(&user.to_uppercase()), &utf16le(domain)],
+    )
+}
+
+/// Builds the AUTHENTICATE message answering the server's CHALLENGE. The
+/// domain comes from a `DOMAIN\user` username.
+fn ntlm_authenticate(challenge_b64: &str, user: &str, pass: &str) -> Option<String> {
+    let msg = base64::engine::general_purpose::STANDARD
+        .decode(challenge_b64)
+        .ok()?;
+    let nonce = uuid::Uuid::new_v4();
+    let client_nonce: [u8; 8] = nonce.as_bytes()[..8].try_into().ok()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let ticks =
+        (now.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000 + now.subsec_nanos() as u64 / 100;
+    let out = ntlm_authenticate_message(&msg, user, pass, &client_nonce, ticks.to_le_bytes())?;
+    Some(format!(
+        "NTLM {}",
+        base64::engine::general_purpose::STANDARD.encode(out)
+    ))
+}
+
+/// The AUTHENTICATE message for the decoded CHALLENGE `msg`. `now` is the
+/// FILETIME used when the server sent no timestamp of its own.
+fn ntlm_authenticate_message(
+    msg: &[u8],
+    user: &str,
+    pass: &str,
+    client_nonce: &[u8; 8],
+    now: [u8; 8],
+) -> Option<Vec<u8>> {
+    if msg.get(..8)? != NTLM_SIGNATURE || msg.get(8..12)? != 2u32.to_le_bytes() {
+        return None;
+    }
+    let server_challenge = msg.get(24..32)?;
+    let target_info = security_buffer(msg, 40).unwrap_or(&[]);
+
+    let (domain, user) = user.split_once('\\').unwrap_or(("", user));
+    let v2_hash = ntowf_v2(user, domain, pass);
+
+    let server_time = av_timestamp(target_info);
+    let timestamp = server_time.unwrap_or(now);
+
+    let mut blob = Vec::with_capacity(32 + target_info.len());
+    blob.extend_from_slice(&[1, 1, 0, 0, 0, 0, 0, 0]);
+    blob.extend_from_slice(&timestamp);
+    blob.extend_from_slice(client_nonce);
+    blob.extend_from_slice(&[0u8; 4]);
+    blob.extend_from_slice(target_info);
+    blob.extend_from_slice(&[0u8; 4]);
+
+    let nt_proof = hmac_md5(&v2_hash, &[server_challenge, &blob]);
+    let mut nt_response = nt_proof.to_vec();
+    nt_response.extend_from_slice(&blob);
+
+    // With a server timestamp the LMv2 response must be zeroed.
+    let lm_response = if server_time.is_some() {
+        vec![0u8; 24]
+    } else {
+        let mut lm = hmac_md5(&v2_hash, &[server_challenge, client_nonce]).to_vec();
+        lm.extend_from_slice(client_nonce);
+        lm
+    };
+
+    let fields: [Vec<u8>; 5] = [
+        lm_response,
+        nt_response,
+        utf16le(domain),
+        utf16le(user),
+        Vec::new(), // workstation
+    ];
+    const HEADER_LEN: usize = 72;
+    let mut out = Vec::with_capacity(HEADER_LEN + fields.iter().map(Vec::len).sum::<usize>());
+    out.extend_from_slice(NTLM_SIGNATURE);
+    out.extend_from_slice(&3u32.to_le_bytes());
+    let mut offset = HEADER_LEN;
+    for field in &fields {
+        out.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    // Empty session key buffer, then flags and an empty version.
+    out.extend_from_slice(&[0u8; 8]);
+    out.extend_from_slice(&NTLM_FLAGS.to_le_bytes());
+    out.extend_from_slice(&[0u8; 8]);
+    for field in &fields {
+        out.extend_from_slice(field);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn param<'a>(header: &'a str, name: &str) -> &'a str {
+        let start = header.find(&format!("{}=", name)).unwrap() + name.len() + 1;
+        let rest = header[start..].trim_start_matches('"');
+        &rest[..rest.find(['"', ',']).unwrap_or(rest.len())]
+    }
+
+    // RFC 7616 §3.9.1.
+    const RFC7616_CHALLENGE: &str =
+        "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", \
+        algorithm={}, nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+        opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"";
+    const RFC7616_CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+    fn rfc7616_response(algorithm: &str) -> String {
+        let value = RFC7616_CHALLENGE.replace("{}", algorithm);
+        let challenges = parse_challenges(&value);
+        let mut challenge = DigestChallenge::parse(&challenges[0]).unwrap();
+        let header = digest_with_cnonce(
+            &mut challenge,
+            "Mufasa",
+            "Circle of Life",
+            "GET",
+            "/dir/index.html",
+            RFC7616_CNONCE,
+        );
+        assert_eq!(param(&header, "nc"), "00000001");
+        assert_eq!(param(&header, "qop"), "auth");
+        assert_eq!(
+            param(&header, "opaque"),
+            "FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS"
+        );
+        param(&header, "response").to_string()
+    }
+
+    #[test]
+    fn digest_matches_rfc7616_md5_example() {
+        assert_eq!(rfc7616_response("MD5"), "8ca523f5e9506fed4657c9700eebdbec");
+    }
+
+    #[test]
+    fn digest_matches_rfc7616_sha256_example() {
+        assert_eq!(
+            rfc7616_response("SHA-256"),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+    }
+
+    #[test]
+    fn digest_counts_nonce_uses() {
+        let challenges = parse_challenges("Digest realm=\"r\", nonce=\"n\", qop=\"auth\"");
+        let mut challenge = DigestChallenge::parse(&challenges[0]).unwrap();
+        digest_with_cnonce(&mut challenge, "u", "p", "CONNECT", "h:443", "c");
+        let header = digest_with_cnonce(&mut challenge, "u", "p", "CONNECT", "h:443", "c");
+        assert_eq!(param(&header, "nc"), "00000002");
+    }
+
+    #[test]
+    fn digest_rejects_auth_int_only_and_unknown_algorithms() {
+        let challenges = parse_challenges(
+            "Digest nonce=\"n\", qop=\"auth-int\", Digest nonce=\"n\", algorithm=SHA-512-256",
+        );
+        assert_eq!(challenges.len(), 2);
+        assert!(challenges
+            .iter()
+            .all(|c| DigestChallenge::parse(c).is_none()));
+    }
+
+    // MS-NLMP §4.2.4: user "User", domain "Domain", password "Password".
+    const NLMP_SERVER_CHALLENGE: &str = "01 23 45 67 89 ab cd ef";
+    const NLMP_TARGET_INFO: &str = "02 00 0c 00 44 00 6f 00 6d 00 61 00 69 00 6e 00
+        01 00 0c 00 53 00 65 00 72 00 76 00 65 00 72 00 00 00 00 00";
+
+    fn nlmp_challenge_message() -> Vec<u8> {
+        let target_info = unhex(NLMP_TARGET_INFO);
+        let mut msg = Vec::new();
+        msg.extend_from_slice(NTLM_SIGNATURE);
+        msg.extend_from_slice(&2u32.to_le_bytes());
+        msg.extend_from_slice(&[0u8; 8]); // target name
+        msg.extend_from_slice(&NTLM_FLAGS.to_le_bytes());
+        msg.extend_from_slice(&unhex(NLMP_SERVER_CHALLENGE));
+        msg.extend_from_slice(&[0u8; 8]); // reserved
+        msg.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        msg.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        msg.extend_from_slice(&48u32.to_le_bytes());
+        msg.extend_from_slice(&target_info);
+        msg
+    }
+
+    #[test]
+    fn ntowf_v2_matches_ms_nlmp() {
+        assert_eq!(
+            ntowf_v2("User", "Domain", "Password").to_vec(),
+            unhex("0c 86 8a 40 3b fd 7a 93 a3 00 1e f2 2e f0 2e 3f")
+        );
+    }
+
+    #[test]
+    fn ntlmv2_authenticate_matches_ms_nlmp() {
+        let out = ntlm_authenticate_message(
+            &nlmp_challenge_message(),
+            "Domain\\User",
+            "Password",
+            &[0xaa; 8],
+            [0; 8],
+        )
+        .unwrap();
+        assert_eq!(&out[..8], NTLM_SIGNATURE);
+        assert_eq!(out[8..12], 3u32.to_le_bytes());
+
+        let lm = security_buffer(&out, 12).unwrap();
+        assert_eq!(
+            lm,
+            unhex("86 c3 50 97 ac 9c ec 10 25 54 76 4a 57 cc cc 19 aa aa aa aa aa aa aa aa")
+        );
+        let nt = security_buffer(&out, 20).unwrap();
+        assert_eq!(
+            nt[..16],
+            unhex("68 cd 0a b8 51 e5 1c 96 aa bc 92 7b eb ef 6a 1c")
+        );
+        let mut blob = unhex("01 01 00 00 00 00 00 00 00 00 00 00 00 00 00 00");
+        blob.extend_from_slice(&[0xaa; 8]);
+        blob.extend_from_slice(&[0; 4]);
+        blob.extend_from_slice(&unhex(NLMP_TARGET_INFO));
+        blob.extend_from_slice(&[0; 4]);
+        assert_eq!(nt[16..], blob);
+
+        assert_eq!(security_buffer(&out, 28).unwrap(), utf16le("Domain"));
+        assert_eq!(security_buffer(&out, 36).unwrap(), utf16le("User"));
+        assert_eq!(security_buffer(&out, 44).unwrap(), b"");
+    }
+
+    #[test]
+    fn ntlm_zeroes_lm_response_with_server_timestamp() {
+        let mut msg = nlmp_challenge_message();
+        // Prepend an MsvAvTimestamp pair to the target info.
+        let mut target_info = vec![7, 0, 8, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        target_info.extend_from_slice(&unhex(NLMP_TARGET_INFO));
+        msg.truncate(48);
+        msg[40..42].copy_from_slice(&(target_info.len() as u16).to_le_bytes());
+        msg[42..44].copy_from_slice(&(target_info.len() as u16).to_le_bytes());
+        msg.extend_from_slice(&target_info);
+
+        let out = ntlm_authenticate_message(&msg, "User", "Password", &[0xaa; 8], [0; 8]).unwrap();
+        assert_eq!(security_buffer(&out, 12).unwrap(), [0u8; 24]);
+        // The blob carries the server's time, not ours.
+        assert_eq!(
+            security_buffer(&out, 20).unwrap()[24..32],
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn ntlm_rejects_other_message_types() {
+        let mut msg = nlmp_challenge_message();
+        msg[8] = 1;
+        assert!(ntlm_authenticate_message(&msg, "User", "Password", &[0; 8], [0; 8]).is_none());
+        assert!(ntlm_authenticate("not base64!", "User", "Password").is_none());
+    }
+
+    #[test]
+    fn parses_several_challenges_in_one_header() {
+        let challenges = parse_challenges(
+            "Negotiate, NTLM, Digest realm=\"a, b\", nonce=\"x\", qop=\"auth,auth-int\", Basic realm=\"r\"",
+        );
+        let schemes: Vec<&str> = challenges.iter().map(|c| c.scheme.as_str()).collect();
+        assert_eq!(schemes, ["negotiate", "ntlm", "digest", "basic"]);
+        assert_eq!(challenges[2].param("realm"), Some("a, b"));
+        assert_eq!(challenges[2].param("nonce"), Some("x"));
+        assert_eq!(challenges[2].param("qop"), Some("auth,auth-int"));
+        assert_eq!(challenges[3].param("realm"), Some("r"));
+    }
+
+    #[test]
+    fn parses_quoted_escapes() {
+        let challenges = parse_challenges(r#"Basic realm="say \"hi\", \\ok""#);
+        assert_eq!(challenges.len(), 1);
+        assert_eq!(challenges[0].param("realm"), Some(r#"say "hi", \ok"#));
+    }
+
+    #[test]
+    fn parses_bare_token68() {
+        let challenges = parse_challenges("NTLM TlRMTVNTUAACAAAADAAMADAAAAA=, Basic realm=x");
+        assert_eq!(challenges.len(), 2);
+        assert_eq!(
+            challenges[0].token.as_deref(),
+            Some("TlRMTVNTUAACAAAADAAMADAAAAA=")
+        );
+        assert!(challenges[0].params.is_empty());
+        let padded = parse_challenges("Negotiate YIIBhgYGKwYBBQUCoIIBejCCAXY==");
+        assert_eq!(
+            padded[0].token.as_deref(),
+            Some("YIIBhgYGKwYBBQUCoIIBejCCAXY==")
+        );
+    }
+
+    #[test]
+    fn prefers_sha256_digest_then_ntlm_then_basic() {
+        let pick = |value: &str| strongest(&parse_challenges(value));
+        let both = pick("Digest nonce=\"a\", Digest nonce=\"b\", algorithm=SHA-256, NTLM");
+        assert!(matches!(both, Some(Scheme::Digest(d)) if d.sha256 && d.nonce == "b"));
+        assert!(matches!(pick("Basic realm=x, NTLM"), Some(Scheme::Ntlm)));
+        assert!(matches!(pick("Basic realm=x"), Some(Scheme::Basic)));
+        assert!(pick("Negotiate").is_none());
+    }
+}
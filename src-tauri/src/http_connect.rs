@@ -0,0 +1,563 @@
+use crate::engine::{join_host_port, Tunnel, UpstreamStream};
+use crate::error::UpstreamError;
+use crate::http_proxy::{parse_response, HttpResponse};
+use crate::state::Proxy;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// HTTP CONNECT client for `http`/`https` upstreams, shared by client traffic,
+// chains and the health checker. Responses are read through a buffer, so
+// bytes the proxy sends right after its `200` are handed on with the tunnel
+// rather than lost.
+
+/// Upper bound on the size of a response status line plus headers.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Largest error body read, to log it or keep the connection for a retry.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// How much of an error body makes it into the log.
+const MAX_LOGGED_BODY: usize = 200;
+
+/// How long the proxy gets to answer each `CONNECT`.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn timed_out() -> UpstreamError {
+    UpstreamError::Io(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "HTTP proxy did not answer CONNECT in time",
+    ))
+}
+
+/// The proxy connection plus whatever has been read but not yet consumed.
+struct ResponseReader {
+    stream: UpstreamStream,
+    buf: Vec<u8>,
+}
+
+impl ResponseReader {
+    async fn fill(&mut self) -> Result<(), UpstreamError> {
+        let mut chunk = [0u8; 4096];
+        let n = self.stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(UpstreamError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "HTTP proxy closed the connection",
+            )));
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    /// Reads one response head, leaving anything after it buffered.
+    async fn read_head(&mut self) -> Result<(HttpResponse, Vec<u8>), UpstreamError> {
+        loop {
+            if let Some(pos) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let rest = self.buf.split_off(pos + 4);
+                let head = std::mem::replace(&mut self.buf, rest);
+                let response = parse_response(&head).ok_or_else(|| {
+                    UpstreamError::Protocol("Invalid HTTP proxy response".to_string())
+                })?;
+                return Ok((response, head));
+            }
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(UpstreamError::Protocol(
+                    "HTTP proxy response header too large".to_string(),
+                ));
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Reads a `Content-Length` body. `None` when the body isn't delimited
+    /// that way or is too large, which also means the connection can't be
+    /// reused.
+    async fn read_body(&mut self, response: &HttpResponse) -> Option<Vec<u8>> {
+        let len = response
+            .header("Content-Length")?
+            .parse::<usize>()
+            .ok()
+            .filter(|len| *len <= MAX_BODY_SIZE)?;
+        while self.buf.len() < len {
+            self.fill().await.ok()?;
+        }
+        let rest = self.buf.split_off(len);
+        Some(std::mem::replace(&mut self.buf, rest))
+    }
+
+    /// The tunnel stream, replaying any bytes read past the response.
+    fn into_stream(self) -> UpstreamStream {
+        if self.buf.is_empty() {
+            self.stream
+        } else {
+            Box::new(Prefixed {
+                prefix: self.buf,
+                pos: 0,
+                inner: self.stream,
+            })
+        }
+    }
+}
+
+/// Whether the proxy keeps the connection open after this response.
+fn keeps_alive(response: &HttpResponse) -> bool {
+    let mut tokens = response
+        .headers_named("Connection")
+        .chain(response.headers_named("Proxy-Connection"))
+        .flat_map(|v| v.split(','))
+        .map(str::trim);
+    if response.version == "HTTP/1.0" {
+        tokens.any(|t| t.eq_ignore_ascii_case("keep-alive"))
+    } else {
+        !tokens.any(|t| t.eq_ignore_ascii_case("close"))
+    }
+}
+
+/// Headers the request builder owns; a proxy's own headers can't replace them.
+const RESERVED_HEADERS: &[&str] = &[
+    "Host",
+    "Proxy-Authorization",
+    "Connection",
+    "Proxy-Connection",
+    "Content-Length",
+    "Transfer-Encoding",
+];
+
+/// Checks a proxy's extra headers before they are stored.
+pub(crate) fn validate_headers(headers: &BTreeMap<String, String>) -> Result<(), String> {
+    for (name, value) in headers {
+        let is_token = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !is_token {
+            return Err(format!("Invalid header name: {:?}", name));
+        }
+        if RESERVED_HEADERS
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("Header {} is set by HydraGate itself", name));
+        }
+        if value.contains(['\r', '\n']) {
+            return Err(format!("Header {} spans several lines", name));
+        }
+    }
+    Ok(())
+}
+
+/// A proxy's extra headers with their placeholders filled in for one request.
+pub(crate) fn expand_headers(
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Vec<(String, String)>, UpstreamError> {
+    if selected.headers.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Client-supplied hostnames end up in header values, so refuse any that
+    // would break out of the header line.
+    if target_host.contains(|c: char| c.is_control()) {
+        return Err(UpstreamError::Protocol(
+            "Target host contains control characters".to_string(),
+        ));
+    }
+    let session_id = uuid::Uuid::new_v4().simple().to_string();
+    let target = join_host_port(target_host, target_port);
+    Ok(selected
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace("{target_host}", target_host)
+                .replace("{target_port}", &target_port.to_string())
+                .replace("{target}", &target)
+                .replace("{session_id}", &session_id);
+            (name.clone(), value)
+        })
+        .collect())
+}
+
+/// Opens a tunnel to `target_host:target_port` through the HTTP proxy at the
+/// other end of `stream`, answering authentication challenges on the way.
+pub(crate) async fn connect(
+    stream: UpstreamStream,
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<Tunnel, UpstreamError> {
+    let target = join_host_port(target_host, target_port);
+    let mut reader = ResponseReader {
+        stream,
+        buf: Vec::new(),
+    };
+    let extra = expand_headers(selected, target_host, target_port)?;
+    let mut auth = crate::http_auth::preemptive(selected, "CONNECT", &target);
+    loop {
+        let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        for (name, value) in &extra {
+            req.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(auth) = &auth {
+            req.push_str(&format!("Proxy-Authorization: {}\r\n", auth.header));
+        }
+        req.push_str("\r\n");
+        reader.stream.write_all(req.as_bytes()).await?;
+
+        let (response, head) = tokio::time::timeout(RESPONSE_TIMEOUT, reader.read_head())
+            .await
+            .map_err(|_| timed_out())??;
+
+        if (200..300).contains(&response.status) {
+            return Ok(Tunnel {
+                stream: reader.into_stream(),
+                bound: None,
+            });
+        }
+
+        let body = tokio::time::timeout(RESPONSE_TIMEOUT, reader.read_body(&response))
+            .await
+            .ok()
+            .flatten();
+
+        if response.status == 407 {
+            auth = Some(crate::http_auth::respond(
+                selected,
+                "CONNECT",
+                &target,
+                &response,
+                auth.as_ref(),
+            )?);
+            if body.is_none() || !keeps_alive(&response) {
+                // The next attempt starts with the scheme learned here.
+                return Err(UpstreamError::AuthRetry);
+            }
+            continue;
+        }
+
+        let snippet: String = body
+            .as_deref()
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default()
+            .trim()
+            .chars()
+            .take(MAX_LOGGED_BODY)
+            .collect();
+        eprintln!(
+            "[Proxy] Error connecting through {}:{}: HTTP Connect failed: {} {}",
+            selected.host,
+            selected.port,
+            String::from_utf8_lossy(&head).lines().next().unwrap_or(""),
+            snippet
+        );
+        return Err(UpstreamError::from_http_status(response.status));
+    }
+}
+
+/// Sends an absolute-form request through the HTTP proxy at the other end of
+/// `stream`, answering authentication challenges like [`connect`]. `head`
+/// renders the request head with a `Proxy-Authorization` value. The request
+/// is only sent again after a `407` when `body` is all of it; otherwise the
+/// response, whatever it is, goes to the client. The returned stream starts
+/// with the response head.
+pub(crate) async fn forward(
+    stream: UpstreamStream,
+    selected: &Proxy,
+    method: &str,
+    uri: &str,
+    head: impl Fn(Option<&str>) -> String,
+    body: &[u8],
+    complete: bool,
+) -> Result<UpstreamStream, UpstreamError> {
+    let mut reader = ResponseReader {
+        stream,
+        buf: Vec::new(),
+    };
+    let mut auth = crate::http_auth::preemptive(selected, method, uri);
+    loop {
+        let req = head(auth.as_ref().map(|a| a.header.as_str()));
+        reader.stream.write_all(req.as_bytes()).await?;
+        reader.stream.write_all(body).await?;
+        if !complete {
+            return Ok(reader.into_stream());
+        }
+
+        // No timeout: the answer may be the origin's, however slow it is.
+        let (response, head) = reader.read_head().await?;
+        if response.status != 407 {
+            reader.buf.splice(0..0, head);
+            return Ok(reader.into_stream());
+        }
+
+        let body = tokio::time::timeout(RESPONSE_TIMEOUT, reader.read_body(&response))
+            .await
+            .ok()
+            .flatten();
+        auth = Some(crate::http_auth::respond(
+            selected,
+            method,
+            uri,
+            &response,
+            auth.as_ref(),
+        )?);
+        if body.is_none() || !keeps_alive(&response) {
+            return Err(UpstreamError::AuthRetry);
+        }
+    }
+}
+
+/// A stream that yields `prefix` before reading from `inner`.
+struct Prefixed {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: UpstreamStream,
+}
+
+impl AsyncRead for Prefixed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = buf.remaining().min(self.prefix.len() - self.pos);
+            buf.put_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Prefixed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    fn proxy(host: &str, user: Option<&str>) -> Proxy {
+        Proxy {
+            protocol: "http".to_string(),
+            host: host.to_string(),
+            port: 8080,
+            user: user.map(str::to_string),
+            pass: user.map(|_| "secret".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Reads one request head from the client side of the test.
+    async fn read_request(server: &mut DuplexStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            assert_eq!(server.read(&mut byte).await.unwrap(), 1);
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Runs `connect` against a proxy that answers each request with the
+    /// next of `responses`, and returns the requests it got.
+    async fn exchange(
+        p: &Proxy,
+        responses: &[&[u8]],
+    ) -> (Result<Tunnel, UpstreamError>, Vec<String>) {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let responses: Vec<Vec<u8>> = responses.iter().map(|r| r.to_vec()).collect();
+        let upstream = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                requests.push(read_request(&mut server).await);
+                server.write_all(&response).await.unwrap();
+            }
+            (requests, server)
+        });
+        let result = connect(Box::new(client), p, "example.com", 443).await;
+        let (requests, _server) = upstream.await.unwrap();
+        (result, requests)
+    }
+
+    #[tokio::test]
+    async fn replays_bytes_sent_right_after_200() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let upstream = tokio::spawn(async move {
+            let request = read_request(&mut server).await;
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nearly")
+                .await
+                .unwrap();
+            let mut ping = [0u8; 4];
+            server.read_exact(&mut ping).await.unwrap();
+            server.write_all(b" late").await.unwrap();
+            (request, ping)
+        });
+        let p = proxy("early.test", None);
+        let mut tunnel = connect(Box::new(client), &p, "example.com", 443)
+            .await
+            .unwrap();
+        tunnel.stream.write_all(b"ping").await.unwrap();
+        let mut data = [0u8; 10];
+        tunnel.stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"early late");
+
+        let (request, ping) = upstream.await.unwrap();
+        assert!(
+            request.starts_with("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n")
+        );
+        assert_eq!(&ping, b"ping");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_head() {
+        let mut response = b"HTTP/1.1 200 OK\r\n".to_vec();
+        while response.len() <= MAX_HEAD_SIZE + 4096 {
+            response.extend_from_slice(format!("X-Pad: {}\r\n", "a".repeat(100)).as_bytes());
+        }
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            read_request(&mut server).await;
+            let _ = server.write_all(&response).await;
+            // Hold the connection open so only the cap can end the read.
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+        let p = proxy("big.test", None);
+        match connect(Box::new(client), &p, "example.com", 443).await {
+            Err(UpstreamError::Protocol(msg)) => assert!(msg.contains("too large"), "{}", msg),
+            other => panic!("expected a protocol error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_any_2xx() {
+        let p = proxy("nocontent.test", None);
+        let (result, requests) = exchange(&p, &[b"HTTP/1.0 204 No Content\r\n\r\n"]).await;
+        assert!(result.is_ok());
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn maps_error_statuses() {
+        let p = proxy("errors.test", None);
+        let (result, _) = exchange(
+            &p,
+            &[b"HTTP/1.1 403 Forbidden\r\nContent-Length: 6\r\n\r\nnope\r\n"],
+        )
+        .await;
+        assert!(matches!(result, Err(UpstreamError::HttpForbidden)));
+        let (result, _) = exchange(&p, &[b"HTTP/1.1 502 Bad Gateway\r\n\r\n"]).await;
+        assert!(matches!(result, Err(UpstreamError::HttpBadGateway)));
+        let (result, _) = exchange(&p, &[b"HTTP/1.1 407 Auth\r\n\r\n"]).await;
+        assert!(matches!(result, Err(UpstreamError::HttpAuthRequired)));
+    }
+
+    #[tokio::test]
+    async fn answers_407_on_a_kept_alive_connection() {
+        let p = proxy("keepalive.test", Some("alice"));
+        let (result, requests) = exchange(
+            &p,
+            &[
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                  Proxy-Authenticate: Digest realm=\"r\", nonce=\"n\", qop=\"auth\"\r\n\
+                  Content-Length: 4\r\n\r\ndeny",
+                b"HTTP/1.1 200 OK\r\n\r\n",
+            ],
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(requests.len(), 2);
+        // Nothing learned yet, so the first attempt is Basic.
+        assert!(requests[0].contains("Proxy-Authorization: Basic "));
+        assert!(requests[1].contains("Proxy-Authorization: Digest username=\"alice\""));
+    }
+
+    #[tokio::test]
+    async fn retries_407_with_connection_close_on_a_new_connection() {
+        let p = proxy("close.test", Some("bob"));
+        let (result, requests) = exchange(
+            &p,
+            &[b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                Proxy-Authenticate: Digest realm=\"r\", nonce=\"n\"\r\n\
+                Connection: close\r\nContent-Length: 0\r\n\r\n"],
+        )
+        .await;
+        assert!(matches!(result, Err(UpstreamError::AuthRetry)));
+        assert_eq!(requests.len(), 1);
+        // The scheme is remembered for the next connection.
+        let next = crate::http_auth::preemptive(&p, "CONNECT", "example.com:443").unwrap();
+        assert!(next.header.starts_with("Digest "));
+    }
+
+    #[tokio::test]
+    async fn forward_leaves_the_response_to_the_client_when_the_body_is_partial() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let upstream = tokio::spawn(async move {
+            let request = read_request(&mut server).await;
+            let mut body = [0u8; 2];
+            server.read_exact(&mut body).await.unwrap();
+            server
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+            (request, body)
+        });
+        let p = proxy("partial.test", Some("carol"));
+        let head = |auth: Option<&str>| {
+            format!(
+                "POST http://example.com/ HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n",
+                auth.unwrap()
+            )
+        };
+        let mut stream = forward(
+            Box::new(client),
+            &p,
+            "POST",
+            "http://example.com/",
+            head,
+            b"ab",
+            false,
+        )
+        .await
+        .unwrap();
+        let mut response = [0u8; 12];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 407");
+
+        let (request, body) = upstream.await.unwrap();
+        assert!(request.contains("Proxy-Authorization: Basic "));
+        assert_eq!(&body, b"ab");
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_connection_headers() {
+        let parse = |head: &str| parse_response(head.as_bytes()).unwrap();
+        assert!(keeps_alive(&parse("HTTP/1.1 407 x\r\n\r\n")));
+        assert!(!keeps_alive(&parse(
+            "HTTP/1.1 407 x\r\nConnection: Keep-Alive, close\r\n\r\n"
+        )));
+        assert!(!keeps_alive(&parse("HTTP/1.0 407 x\r\n\r\n")));
+        assert!(keeps_alive(&parse(
+            "HTTP/1.0 407 x\r\nProxy-Connection: keep-alive\r\n\r\n"
+        )));
+    }
+}
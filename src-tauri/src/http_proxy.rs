@@ -0,0 +1,753 @@
+use crate::engine::{
+    connect_proxy, dial_upstream, dial_with, join_host_port, open_client_tunnel, select_upstream,
+    ClientContext, UpstreamStream,
+};
+use crate::error::UpstreamError;
+use crate::http_connect::expand_headers;
+use crate::state::Proxy;
+use dashmap::DashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Upper bound on the size of a request line plus headers.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// A parsed HTTP/1.x request head.
+pub(crate) struct HttpRequest {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Reads until the blank line terminating an HTTP head. Returns the head
+/// (including the terminating `\r\n\r\n`) and any bytes read past it.
+pub(crate) async fn read_head<S: AsyncReadExt + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(pos) = find_subsequence(&buf, b"\r\n\r\n") {
+            let rest = buf.split_off(pos + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "HTTP header too large",
+            ));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed before end of HTTP header",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+pub(crate) fn parse_request(head: &[u8]) -> Option<HttpRequest> {
+    let text = std::str::from_utf8(head).ok()?;
+    let mut lines = text.split("\r\n");
+
+    let mut parts = lines.next()?.split_whitespace();
+    let method = parts.next()?.to_string();
+    let uri = parts.next()?.to_string();
+    let version = parts.next()?.to_string();
+    if parts.next().is_some() || !version.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Some(HttpRequest {
+        method,
+        uri,
+        version,
+        headers,
+    })
+}
+
+/// A parsed HTTP/1.x response head.
+pub(crate) struct HttpResponse {
+    pub version: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of a header that may be repeated.
+    pub fn headers_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub(crate) fn parse_response(head: &[u8]) -> Option<HttpResponse> {
+    let text = std::str::from_utf8(head).ok()?;
+    let mut lines = text.split("\r\n");
+
+    let mut parts = lines.next()?.split_whitespace();
+    let version = parts.next()?.to_string();
+    let status = parts.next()?.parse().ok()?;
+    if !version.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Some(HttpResponse {
+        version,
+        status,
+        headers,
+    })
+}
+
+/// Splits an authority (`host:port` or `[v6]:port`) into its parts.
+pub(crate) fn split_authority(authority: &str, default_port: Option<u16>) -> Option<(String, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, tail) = rest.split_once(']')?;
+        let port = match tail.strip_prefix(':') {
+            Some(p) => p.parse().ok()?,
+            None if tail.is_empty() => default_port?,
+            None => return None,
+        };
+        return Some((host.to_string(), port));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => Some((host.to_string(), port.parse().ok()?)),
+        Some(_) => None,
+        None if !authority.is_empty() => Some((authority.to_string(), default_port?)),
+        None => None,
+    }
+}
+
+/// Headers that only apply to a single hop (RFC 7230 §6.1). `Transfer-Encoding`
+/// is deliberately kept: the body is relayed untouched, so its framing must
+/// survive.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+/// An absolute-form request target (`http://host[:port]/path?query`).
+struct AbsoluteUri {
+    host: String,
+    port: u16,
+    /// Origin-form path, always starting with `/`.
+    path: String,
+}
+
+fn parse_absolute_uri(uri: &str) -> Option<AbsoluteUri> {
+    let (scheme, rest) = uri.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") {
+        return None;
+    }
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => (&rest[..i], rest[i..].to_string()),
+        Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+        None => (rest, "/".to_string()),
+    };
+    // Drop any userinfo; it is never meant for the origin server.
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+    let (host, port) = split_authority(authority, Some(80))?;
+    Some(AbsoluteUri { host, port, path })
+}
+
+impl AbsoluteUri {
+    /// The URI as forwarded to an HTTP upstream, without any userinfo.
+    fn absolute_form(&self) -> String {
+        let authority = if self.port != 80 {
+            join_host_port(&self.host, self.port)
+        } else if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        format!("http://{}{}", authority, self.path)
+    }
+}
+
+/// Whether the request body is all in `body`, so the request can be sent
+/// again after a `407`.
+fn is_complete(req: &HttpRequest, body: &[u8]) -> bool {
+    if req.header("Transfer-Encoding").is_some() {
+        return false;
+    }
+    match req.header("Content-Length") {
+        Some(len) => len.parse::<usize>().is_ok_and(|len| len <= body.len()),
+        None => true,
+    }
+}
+
+/// Methods a server may see twice without harm (RFC 9110 §9.2.2), the only
+/// ones worth racing against a second upstream.
+fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"]
+        .iter()
+        .any(|m| m.eq_ignore_ascii_case(method))
+}
+
+/// Rebuilds the request head without hop-by-hop headers, forcing
+/// `Connection: close` so the relay ends with the response.
+fn rewrite_request(
+    req: &HttpRequest,
+    request_target: &str,
+    extra_headers: &[(String, String)],
+) -> String {
+    let connection_tokens: Vec<String> = req
+        .headers
+        .iter()
+        .filter(|(k, _)| {
+            k.eq_ignore_ascii_case("Connection") || k.eq_ignore_ascii_case("Proxy-Connection")
+        })
+        .flat_map(|(_, v)| v.split(',').map(|t| t.trim().to_string()))
+        .collect();
+
+    let is_hop_by_hop = |name: &str| {
+        HOP_BY_HOP_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(name))
+            || connection_tokens
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(name))
+    };
+
+    let mut out = format!("{} {} {}\r\n", req.method, request_target, req.version);
+    for (name, value) in &req.headers {
+        if !is_hop_by_hop(name) {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    for (name, value) in extra_headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    out
+}
+
+/// Checks `Proxy-Authorization: Basic` against the inbound user list. An
+/// empty list means the listener is open.
+fn is_authorized(req: &HttpRequest, auth_users: &DashMap<String, String>) -> bool {
+    if auth_users.is_empty() {
+        return true;
+    }
+    let Some(value) = req.header("Proxy-Authorization") else {
+        return false;
+    };
+    let Some((scheme, encoded)) = value.split_once(' ') else {
+        return false;
+    };
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return false;
+    }
+
+    use base64::Engine;
+    let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+        return false;
+    };
+    let decoded = String::from_utf8_lossy(&decoded);
+    let Some((user, pass)) = decoded.split_once(':') else {
+        return false;
+    };
+    auth_users
+        .get(user)
+        .map(|p| p.value() == pass)
+        .unwrap_or(false)
+}
+
+async fn respond(
+    client: &mut TcpStream,
+    status: u16,
+    reason: &str,
+    extra_headers: &str,
+) -> std::io::Result<()> {
+    let resp = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason, extra_headers
+    );
+    client.write_all(resp.as_bytes()).await
+}
+
+/// Tells the client why its upstream could not be reached.
+async fn respond_upstream_error(client: &mut TcpStream, e: &UpstreamError) -> std::io::Result<()> {
+    match e {
+        UpstreamError::Rejected => respond(client, 403, "Forbidden", "").await,
+        _ => respond(client, 502, "Bad Gateway", "").await,
+    }
+}
+
+fn request_error(kind: std::io::ErrorKind, msg: &str) -> std::io::Error {
+    std::io::Error::new(kind, msg.to_string())
+}
+
+// ─── HTTP inbound handler ───────────────────────────────────────────────────
+
+pub(crate) async fn handle_http(client: &mut TcpStream, ctx: ClientContext) -> std::io::Result<()> {
+    let (head, rest) = read_head(client).await?;
+
+    let req = match parse_request(&head) {
+        Some(r) => r,
+        None => {
+            respond(client, 400, "Bad Request", "").await?;
+            return Err(request_error(
+                std::io::ErrorKind::InvalidData,
+                "Malformed HTTP request",
+            ));
+        }
+    };
+
+    if !is_authorized(&req, &ctx.auth_users) {
+        respond(
+            client,
+            407,
+            "Proxy Authentication Required",
+            "Proxy-Authenticate: Basic realm=\"HydraGate\"\r\n",
+        )
+        .await?;
+        return Err(request_error(
+            std::io::ErrorKind::PermissionDenied,
+            "Invalid proxy credentials",
+        ));
+    }
+
+    if !req.method.eq_ignore_ascii_case("CONNECT") {
+        return forward_request(client, ctx, req, rest).await;
+    }
+
+    let (target_host, target_port) = match split_authority(&req.uri, None) {
+        Some(t) => t,
+        None => {
+            respond(client, 400, "Bad Request", "").await?;
+            return Err(request_error(
+                std::io::ErrorKind::InvalidData,
+                "Invalid CONNECT target",
+            ));
+        }
+    };
+
+    let selected = match select_upstream(&ctx, &target_host, target_port) {
+        Some(p) => p,
+        None => {
+            respond(client, 503, "Service Unavailable", "").await?;
+            return Err(request_error(
+                std::io::ErrorKind::NotFound,
+                "No alive proxies",
+            ));
+        }
+    };
+
+    match dial_upstream(&ctx, selected, &target_host, target_port).await {
+        Ok(mut tunnel) => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            if !rest.is_empty() {
+                tunnel.stream.write_all(&rest).await?;
+            }
+            tokio::io::copy_bidirectional(client, &mut tunnel.stream).await?;
+            Ok(())
+        }
+        Err(e) => {
+            respond_upstream_error(client, &e).await?;
+            Err(e.into())
+        }
+    }
+}
+
+/// Relays an absolute-form request (`GET http://host/path HTTP/1.1`). SOCKS
+/// upstreams get a tunnel to the origin and an origin-form request; HTTP
+/// upstreams get the absolute-form request, userinfo removed.
+async fn forward_request(
+    client: &mut TcpStream,
+    ctx: ClientContext,
+    req: HttpRequest,
+    rest: Vec<u8>,
+) -> std::io::Result<()> {
+    let uri = match parse_absolute_uri(&req.uri) {
+        Some(u) => u,
+        None => {
+            respond(client, 400, "Bad Request", "").await?;
+            return Err(request_error(
+                std::io::ErrorKind::InvalidData,
+                "Expected an absolute http:// request URI",
+            ));
+        }
+    };
+
+    let selected = match select_upstream(&ctx, &uri.host, uri.port) {
+        Some(p) => p,
+        None => {
+            respond(client, 503, "Service Unavailable", "").await?;
+            return Err(request_error(
+                std::io::ErrorKind::NotFound,
+                "No alive proxies",
+            ));
+        }
+    };
+
+    let complete = is_complete(&req, &rest);
+    let (req, uri, rest) = (&req, &uri, &rest);
+    let ctx = &ctx;
+    let hedge = is_idempotent(&req.method);
+    let upstream = dial_with(
+        ctx,
+        selected,
+        &uri.host,
+        uri.port,
+        hedge,
+        |_| true,
+        |p| async move { send_request(ctx, &p, req, uri, rest, complete).await },
+    )
+    .await;
+
+    match upstream {
+        Ok(mut upstream) => {
+            tokio::io::copy_bidirectional(client, &mut upstream).await?;
+            Ok(())
+        }
+        Err(e) => {
+            respond_upstream_error(client, &e).await?;
+            Err(e.into())
+        }
+    }
+}
+
+/// Sends the request through `selected`, returning the connection its
+/// response arrives on. A proxy that hangs up on its `407` gets a second
+/// connection, which starts with the scheme it asked for.
+async fn send_request(
+    ctx: &ClientContext,
+    selected: &Proxy,
+    req: &HttpRequest,
+    uri: &AbsoluteUri,
+    body: &[u8],
+    complete: bool,
+) -> Result<UpstreamStream, UpstreamError> {
+    match send_request_once(ctx, selected, req, uri, body, complete).await {
+        Err(UpstreamError::AuthRetry) => {
+            match send_request_once(ctx, selected, req, uri, body, complete).await {
+                Err(UpstreamError::AuthRetry) => Err(UpstreamError::AuthRejected),
+                result => result,
+            }
+        }
+        result => result,
+    }
+}
+
+async fn send_request_once(
+    ctx: &ClientContext,
+    selected: &Proxy,
+    req: &HttpRequest,
+    uri: &AbsoluteUri,
+    body: &[u8],
+    complete: bool,
+) -> Result<UpstreamStream, UpstreamError> {
+    // NTLM authenticates a connection, which can't be done while relaying a
+    // request verbatim, so such proxies get a tunnel like SOCKS ones.
+    if selected.is_http() && !crate::http_auth::is_connection_bound(selected) {
+        let target = uri.absolute_form();
+        let extra = expand_headers(selected, &uri.host, uri.port)?;
+        let head = |auth: Option<&str>| {
+            let mut extra = extra.clone();
+            if let Some(auth) = auth {
+                extra.push(("Proxy-Authorization".to_string(), auth.to_string()));
+            }
+            rewrite_request(req, &target, &extra)
+        };
+        let forward = |st| {
+            crate::http_connect::forward(st, selected, &req.method, &target, &head, body, complete)
+        };
+        if let Some(st) = ctx.warm.take(selected) {
+            match forward(st).await {
+                // The proxy dropped the idle connection: dial afresh.
+                Err(UpstreamError::Io(e)) if e.kind() != std::io::ErrorKind::TimedOut => {}
+                result => return result,
+            }
+        }
+        return forward(connect_proxy(selected).await?).await;
+    }
+
+    let mut extra = Vec::new();
+    if req.header("Host").is_none() {
+        extra.push(("Host".to_string(), join_host_port(&uri.host, uri.port)));
+    }
+    let head = rewrite_request(req, &uri.path, &extra);
+    let mut tunnel = open_client_tunnel(ctx, selected, &uri.host, uri.port).await?;
+    tunnel.stream.write_all(head.as_bytes()).await?;
+    tunnel.stream.write_all(body).await?;
+    Ok(tunnel.stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_heads() {
+        let req = parse_request(
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Connection:  keep-alive \r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(req.method, "CONNECT");
+        assert_eq!(req.uri, "example.com:443");
+        assert_eq!(req.version, "HTTP/1.1");
+        assert_eq!(req.header("host"), Some("example.com:443"));
+        assert_eq!(req.header("PROXY-CONNECTION"), Some("keep-alive"));
+        assert_eq!(req.header("Proxy-Authorization"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_request_heads() {
+        assert!(parse_request(b"CONNECT example.com:443\r\n\r\n").is_none());
+        assert!(parse_request(b"CONNECT a:1 HTTP/1.1 extra\r\n\r\n").is_none());
+        assert!(parse_request(b"GET / HTTP/2\r\n\r\n").is_none());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nno colon\r\n\r\n").is_none());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n").is_none());
+        assert!(parse_request(b"").is_none());
+    }
+
+    #[test]
+    fn splits_authorities() {
+        let split = |a: &str, default: Option<u16>| split_authority(a, default);
+        assert_eq!(
+            split("example.com:443", None),
+            Some(("example.com".into(), 443))
+        );
+        assert_eq!(
+            split("example.com", Some(80)),
+            Some(("example.com".into(), 80))
+        );
+        assert_eq!(split("example.com", None), None);
+        assert_eq!(split("[::1]:8080", None), Some(("::1".into(), 8080)));
+        assert_eq!(
+            split("[2001:db8::1]", Some(80)),
+            Some(("2001:db8::1".into(), 80))
+        );
+        assert_eq!(split("[::1]8080", None), None);
+        assert_eq!(split("[::1", None), None);
+        assert_eq!(split(":443", None), None);
+        assert_eq!(split("example.com:http", None), None);
+        assert_eq!(split("example.com:65536", None), None);
+        assert_eq!(split("", Some(80)), None);
+    }
+
+    #[tokio::test]
+    async fn read_head_keeps_bytes_past_the_head() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"CONNECT a:1 HTTP/1.1\r\n\r\n\x16\x03\x01")
+            .await
+            .unwrap();
+        let (head, rest) = read_head(&mut server).await.unwrap();
+        assert_eq!(head, b"CONNECT a:1 HTTP/1.1\r\n\r\n");
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn read_head_caps_the_head_size() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client
+            .write_all(&[b'a'; MAX_HEAD_SIZE + 2048])
+            .await
+            .unwrap();
+        let err = read_head(&mut server).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        drop(client);
+        let (client, mut server) = tokio::io::duplex(1024);
+        drop(client);
+        let err = read_head(&mut server).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn checks_basic_proxy_credentials() {
+        let users = DashMap::new();
+        let req = |auth: Option<&str>| {
+            let mut head = "CONNECT a:1 HTTP/1.1\r\n".to_string();
+            if let Some(auth) = auth {
+                head.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+            }
+            head.push_str("\r\n");
+            parse_request(head.as_bytes()).unwrap()
+        };
+        // No users configured: open.
+        assert!(is_authorized(&req(None), &users));
+
+        users.insert("alice".to_string(), "pa:ss".to_string());
+        // "alice:pa:ss"
+        assert!(is_authorized(&req(Some("Basic YWxpY2U6cGE6c3M=")), &users));
+        assert!(is_authorized(
+            &req(Some("basic  YWxpY2U6cGE6c3M= ")),
+            &users
+        ));
+        // "alice:wrong"
+        assert!(!is_authorized(&req(Some("Basic YWxpY2U6d3Jvbmc=")), &users));
+        assert!(!is_authorized(
+            &req(Some("Bearer YWxpY2U6cGE6c3M=")),
+            &users
+        ));
+        assert!(!is_authorized(&req(Some("Basic !!!")), &users));
+        assert!(!is_authorized(&req(None), &users));
+    }
+
+    #[test]
+    fn parses_absolute_uris() {
+        let uri = parse_absolute_uri("http://example.com/a/b?c=d").unwrap();
+        assert_eq!((uri.host.as_str(), uri.port), ("example.com", 80));
+        assert_eq!(uri.path, "/a/b?c=d");
+
+        let uri = parse_absolute_uri("HTTP://user:pw@[::1]:8080").unwrap();
+        assert_eq!((uri.host.as_str(), uri.port), ("::1", 8080));
+        assert_eq!(uri.path, "/");
+
+        let uri = parse_absolute_uri("http://example.com?q=1").unwrap();
+        assert_eq!(uri.path, "/?q=1");
+        assert_eq!(uri.absolute_form(), "http://example.com/?q=1");
+
+        let uri = parse_absolute_uri("http://user:pw@[::1]/a").unwrap();
+        assert_eq!(uri.absolute_form(), "http://[::1]/a");
+        let uri = parse_absolute_uri("http://user:pw@example.com:8080/a").unwrap();
+        assert_eq!(uri.absolute_form(), "http://example.com:8080/a");
+
+        assert!(parse_absolute_uri("https://example.com/").is_none());
+        assert!(parse_absolute_uri("/relative").is_none());
+        assert!(parse_absolute_uri("http:///path").is_none());
+    }
+
+    #[test]
+    fn rewrites_requests_without_hop_by_hop_headers() {
+        let req = parse_request(
+            b"GET http://example.com/x HTTP/1.1\r\nHost: example.com\r\n\
+              Proxy-Authorization: Basic abc\r\nConnection: keep-alive, X-Trace\r\n\
+              X-Trace: 1\r\nKeep-Alive: 300\r\nTransfer-Encoding: chunked\r\n\
+              Accept: */*\r\n\r\n",
+        )
+        .unwrap();
+        let extra = [("X-Session".to_string(), "42".to_string())];
+        assert_eq!(
+            rewrite_request(&req, "/x", &extra),
+            "GET /x HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\
+             Accept: */*\r\nX-Session: 42\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn knows_when_the_body_is_complete() {
+        let req = |head: &str| parse_request(head.as_bytes()).unwrap();
+        assert!(is_complete(&req("GET http://a/ HTTP/1.1\r\n\r\n"), b""));
+        let post = req("POST http://a/ HTTP/1.1\r\nContent-Length: 4\r\n\r\n");
+        assert!(is_complete(&post, b"data"));
+        assert!(!is_complete(&post, b"da"));
+        let chunked = req("POST http://a/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert!(!is_complete(&chunked, b"0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn forwards_with_failover_and_digest_retry() {
+        use crate::state::ProxyPool;
+        use tokio::net::TcpListener;
+
+        // A proxy that is gone, then one that wants Digest and hangs up on
+        // its 407.
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_port = dead.local_addr().unwrap().port();
+        drop(dead);
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_port = live.local_addr().unwrap().port();
+        let upstream = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in [
+                &b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                   Proxy-Authenticate: Digest realm=\"r\", nonce=\"n\", qop=\"auth\"\r\n\
+                   Connection: close\r\nContent-Length: 0\r\n\r\n"[..],
+                &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi"[..],
+            ] {
+                let (mut st, _) = live.accept().await.unwrap();
+                let (head, _) = read_head(&mut st).await.unwrap();
+                requests.push(String::from_utf8(head).unwrap());
+                st.write_all(response).await.unwrap();
+            }
+            requests
+        });
+
+        let pool = ProxyPool::new();
+        for (id, port) in [("a-dead", dead_port), ("b-live", live_port)] {
+            pool.add(Proxy {
+                id: id.to_string(),
+                protocol: "http".to_string(),
+                host: "127.0.0.1".to_string(),
+                port,
+                user: Some("alice".to_string()),
+                pass: Some("secret".to_string()),
+                is_alive: true,
+                ..Default::default()
+            });
+        }
+        let ctx = ClientContext::for_pool(pool.clone());
+
+        let inbound = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(inbound.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut accepted, _) = inbound.accept().await.unwrap();
+        let handler = tokio::spawn(async move { handle_http(&mut accepted, ctx).await });
+        client
+            .write_all(b"GET http://user:pw@example.com/x HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        drop(client);
+        handler.await.unwrap().unwrap();
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"hi"));
+        assert!(pool.is_penalized("a-dead"));
+        let requests = upstream.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("GET http://example.com/x HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Proxy-Authorization: Basic "));
+        assert!(requests[1].contains("Proxy-Authorization: Digest username=\"alice\""));
+        assert!(requests[1].contains("uri=\"http://example.com/x\""));
+    }
+}
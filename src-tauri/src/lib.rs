@@ -1,30 +1,79 @@
+pub mod chain;
 pub mod commands;
 pub mod crypto;
 pub mod engine;
+pub mod error;
+pub mod geoip;
+pub mod groups;
+pub mod http_auth;
+pub mod http_connect;
+pub mod http_proxy;
+pub mod port_map;
+pub mod rules;
+pub mod socks;
+pub mod ss;
+pub mod ssh;
 pub mod state;
+pub mod tls;
+pub mod transparent;
+pub mod udp;
+pub mod warm;
 
 use crate::engine::{start_health_checker, ProxyServer};
+use crate::geoip::GeoIp;
+use crate::groups::Groups;
+use crate::port_map::{start_port_mapper, PortMap};
+use crate::rules::Rules;
 use crate::state::ProxyPool;
+use crate::warm::{start_warmer, WarmPool};
+use tauri::Manager;
 
 const DEFAULT_LISTEN_PORT: u16 = 10808;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let pool = ProxyPool::new();
-    let server = ProxyServer::new(pool.clone(), DEFAULT_LISTEN_PORT);
+    let rules = Rules::default();
+    let groups = Groups::default();
+    let warm = WarmPool::default();
+    let server = ProxyServer::new(
+        pool.clone(),
+        rules.clone(),
+        groups.clone(),
+        warm.clone(),
+        DEFAULT_LISTEN_PORT,
+    );
+    let port_map = PortMap::new(pool.clone(), warm.clone());
+    let geoip = GeoIp::default();
 
     let pool_clone = pool.clone();
+    let geoip_clone = geoip.clone();
+    let warm_clone = warm.clone();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .setup(move |_app| {
-            start_health_checker(pool_clone);
+        .setup(move |app| {
+            start_health_checker(pool_clone.clone(), geoip_clone);
+            start_warmer(warm_clone, pool_clone);
+            let port_map = app.state::<PortMap>().inner().clone();
+            port_map.load(app.path().app_data_dir()?);
+            app.state::<Rules>().load(app.path().app_data_dir()?);
+            app.state::<Groups>().load(app.path().app_data_dir()?);
+            app.state::<GeoIp>().load(app.path().app_data_dir()?);
+            start_port_mapper(port_map);
             Ok(())
         })
         .manage(pool)
         .manage(server)
+        .manage(port_map)
+        .manage(rules)
+        .manage(groups)
+        .manage(geoip)
+        .manage(warm)
         .invoke_handler(tauri::generate_handler![
             commands::add_proxy,
+            commands::add_chain,
+            commands::update_chain,
             commands::get_proxy_list,
             commands::toggle_listener,
             commands::get_listen_port,
@@ -33,6 +82,42 @@ pub fn run() {
             commands::set_listen_host,
             commands::get_rotation_mode,
             commands::set_rotation_mode,
+            commands::get_failover_retries,
+            commands::set_failover_retries,
+            commands::get_hedge_delay,
+            commands::set_hedge_delay,
+            commands::get_fallback,
+            commands::set_fallback,
+            commands::get_inbound_protocol,
+            commands::set_inbound_protocol,
+            commands::list_listeners,
+            commands::create_listener,
+            commands::update_listener,
+            commands::delete_listener,
+            commands::start_listener,
+            commands::stop_listener,
+            commands::get_port_map_settings,
+            commands::set_port_map_settings,
+            commands::get_port_map,
+            commands::list_groups,
+            commands::add_group,
+            commands::update_group,
+            commands::remove_group,
+            commands::get_rules,
+            commands::add_rule,
+            commands::update_rule,
+            commands::remove_rule,
+            commands::move_rule,
+            commands::match_rule,
+            commands::set_proxy_tags,
+            commands::get_geoip_databases,
+            commands::set_geoip_databases,
+            commands::reload_geoip,
+            commands::get_warm_pool_settings,
+            commands::set_warm_pool_settings,
+            commands::get_auth_users,
+            commands::set_auth_user,
+            commands::remove_auth_user,
             commands::remove_proxy,
             commands::clear_proxies,
             commands::clear_dead_proxies,
@@ -0,0 +1,407 @@
+use crate::engine::{join_host_port, serve_listener, ClientContext};
+use crate::groups::Groups;
+use crate::state::{Fallback, InboundProtocol, Proxy, ProxyPool, RotationMode};
+use crate::warm::WarmPool;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Port-per-proxy mode: every upstream in the pool gets its own local port in
+// `base_port .. base_port + size`, serving that upstream only and requiring
+// no inbound auth. Ports are assigned by endpoint (not by the per-session
+// proxy id) and persisted, so a proxy keeps its port across restarts.
+
+const PORT_MAP_FILE: &str = "port_map.json";
+const RECONCILE_INTERVAL_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortMapSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub base_port: u16,
+    /// Number of ports in the range, i.e. how many proxies can be mapped.
+    pub size: u16,
+}
+
+impl Default for PortMapSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            base_port: 20000,
+            size: 100,
+        }
+    }
+}
+
+impl PortMapSettings {
+    pub fn contains(&self, port: u16) -> bool {
+        self.enabled
+            && port >= self.base_port
+            && (port as u32) < self.base_port as u32 + self.size as u32
+    }
+
+    /// Checks that the range is non-empty, unprivileged and fits in `u16`.
+    pub fn validate_range(&self) -> Result<(), String> {
+        if self.base_port < 1024 {
+            return Err("Port must be >= 1024".to_string());
+        }
+        if self.size == 0 {
+            return Err("Port range size must be at least 1".to_string());
+        }
+        if self.base_port as u32 + self.size as u32 - 1 > u16::MAX as u32 {
+            return Err("Port range exceeds 65535".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// One row of the port → proxy table.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortMapping {
+    pub port: u16,
+    pub proxy_id: String,
+    pub protocol: String,
+    pub host: String,
+    pub proxy_port: u16,
+    pub is_alive: bool,
+    pub is_listening: bool,
+}
+
+/// What is persisted: the settings plus endpoint → port offset.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PortMapFile {
+    #[serde(default)]
+    settings: PortMapSettings,
+    #[serde(default)]
+    slots: BTreeMap<String, u16>,
+}
+
+/// A port that is currently being served.
+struct ActivePort {
+    host: String,
+    port: u16,
+    /// The pool id behind this endpoint; ids change when a proxy is removed
+    /// and re-added, the port does not.
+    proxy_id: Arc<Mutex<String>>,
+    running: Arc<AtomicBool>,
+}
+
+/// Stable identity of an upstream: the same exit, whatever its pool id.
+fn endpoint_key(p: &Proxy) -> String {
+    format!(
+        "{}://{}@{}",
+        p.protocol,
+        p.user.as_deref().unwrap_or(""),
+        join_host_port(&p.host, p.port)
+    )
+}
+
+#[derive(Clone)]
+pub struct PortMap {
+    pool: ProxyPool,
+    warm: WarmPool,
+    state: Arc<Mutex<PortMapFile>>,
+    storage: Arc<Mutex<Option<PathBuf>>>,
+    active: Arc<DashMap<String, ActivePort>>,
+}
+
+impl PortMap {
+    pub fn new(pool: ProxyPool, warm: WarmPool) -> Self {
+        Self {
+            pool,
+            warm,
+            state: Arc::new(Mutex::new(PortMapFile::default())),
+            storage: Arc::new(Mutex::new(None)),
+            active: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Loads the persisted table from `dir` and saves there from now on.
+    pub fn load(&self, dir: PathBuf) {
+        let path = dir.join(PORT_MAP_FILE);
+        match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<PortMapFile>(&bytes) {
+                Ok(mut file) => {
+                    // Older versions allowed 0.0.0.0, which exposed the
+                    // unauthenticated ports to the network.
+                    if file.settings.host != "127.0.0.1" {
+                        eprintln!(
+                            "[PortMap] Moving ports from {} to 127.0.0.1",
+                            file.settings.host
+                        );
+                        file.settings.host = "127.0.0.1".to_string();
+                    }
+                    if let Err(e) = file.settings.validate_range() {
+                        eprintln!("[PortMap] Resetting invalid settings: {}", e);
+                        file.settings = PortMapSettings::default();
+                    }
+                    let size = file.settings.size;
+                    file.slots.retain(|_, offset| *offset < size);
+                    *self.state.lock().unwrap() = file;
+                }
+                Err(e) => eprintln!("[PortMap] Ignoring corrupt {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("[PortMap] Cannot read {}: {}", path.display(), e),
+        }
+        *self.storage.lock().unwrap() = Some(path);
+    }
+
+    fn save(&self) {
+        let Some(path) = self.storage.lock().unwrap().clone() else {
+            return;
+        };
+        let json = serde_json::to_vec_pretty(&*self.state.lock().unwrap());
+        let result = json.map_err(std::io::Error::other).and_then(|bytes| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, bytes)
+        });
+        if let Err(e) = result {
+            eprintln!("[PortMap] Cannot write {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn get_settings(&self) -> PortMapSettings {
+        self.state.lock().unwrap().settings.clone()
+    }
+
+    /// Applies new settings. Running ports are closed here and reopened by
+    /// the mapper's next pass, once the old sockets are released.
+    pub fn set_settings(&self, settings: PortMapSettings) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let size = settings.size;
+            state.slots.retain(|_, offset| *offset < size);
+            state.settings = settings;
+        }
+        self.save();
+        self.stop_all();
+    }
+
+    /// The current port → proxy table, ordered by port.
+    pub fn get_mappings(&self) -> Vec<PortMapping> {
+        let (base, slots) = {
+            let state = self.state.lock().unwrap();
+            (state.settings.base_port, state.slots.clone())
+        };
+        let mut proxies = self.pool.get_all();
+        proxies.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut rows: Vec<PortMapping> = Vec::new();
+        for p in proxies {
+            let key = endpoint_key(&p);
+            let Some(offset) = slots.get(&key) else {
+                continue;
+            };
+            let port = base.saturating_add(*offset);
+            if rows.iter().any(|r| r.port == port) {
+                continue;
+            }
+            let is_listening = self
+                .active
+                .get(&key)
+                .map(|a| a.port == port && a.running.load(Ordering::SeqCst))
+                .unwrap_or(false);
+            rows.push(PortMapping {
+                port,
+                proxy_id: p.id,
+                protocol: p.protocol,
+                host: p.host,
+                proxy_port: p.port,
+                is_alive: p.is_alive,
+                is_listening,
+            });
+        }
+        rows.sort_by_key(|r| r.port);
+        rows
+    }
+
+    fn stop_all(&self) {
+        for a in self.active.iter() {
+            a.running.store(false, Ordering::SeqCst);
+        }
+        self.active.clear();
+    }
+
+    /// Gives every endpoint in the pool a port and opens or closes listeners
+    /// to match. Endpoints that leave the pool keep their slot until the range
+    /// runs out, so a proxy that comes back gets its old port.
+    fn reconcile(&self) {
+        let settings = self.get_settings();
+        if !settings.enabled {
+            self.stop_all();
+            return;
+        }
+
+        let mut proxies = self.pool.get_all();
+        proxies.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut wanted: BTreeMap<String, String> = BTreeMap::new();
+        for p in &proxies {
+            wanted
+                .entry(endpoint_key(p))
+                .or_insert_with(|| p.id.clone());
+        }
+
+        // Assign slots to new endpoints.
+        let mut changed = false;
+        let slots = {
+            let mut state = self.state.lock().unwrap();
+            for key in wanted.keys() {
+                if state.slots.contains_key(key) {
+                    continue;
+                }
+                let used: Vec<u16> = state.slots.values().copied().collect();
+                let free = (0..settings.size).find(|o| !used.contains(o));
+                let offset = match free {
+                    Some(o) => o,
+                    None => {
+                        // Range full: reclaim a slot whose endpoint is gone.
+                        let stale = state
+                            .slots
+                            .iter()
+                            .filter(|(k, _)| !wanted.contains_key(*k))
+                            .min_by_key(|(_, o)| **o)
+                            .map(|(k, o)| (k.clone(), *o));
+                        match stale {
+                            Some((k, o)) => {
+                                state.slots.remove(&k);
+                                o
+                            }
+                            None => {
+                                eprintln!(
+                                    "[PortMap] No free port for {} in {}-{}",
+                                    key,
+                                    settings.base_port,
+                                    settings.base_port as u32 + settings.size as u32 - 1
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                };
+                state.slots.insert(key.clone(), offset);
+                changed = true;
+            }
+            state.slots.clone()
+        };
+        if changed {
+            self.save();
+        }
+
+        // Close ports whose endpoint left, moved, or failed to bind.
+        self.active.retain(|key, a| {
+            let keep = a.running.load(Ordering::SeqCst)
+                && a.host == settings.host
+                && wanted.contains_key(key)
+                && slots.get(key).map(|o| settings.base_port + o) == Some(a.port);
+            if !keep {
+                a.running.store(false, Ordering::SeqCst);
+            }
+            keep
+        });
+
+        // Open ports for mapped endpoints, and follow pool id changes.
+        for (key, proxy_id) in wanted {
+            let Some(offset) = slots.get(&key) else {
+                continue;
+            };
+            if let Some(a) = self.active.get(&key) {
+                *a.proxy_id.lock().unwrap() = proxy_id;
+                continue;
+            }
+            let port = settings.base_port + offset;
+            let active = ActivePort {
+                host: settings.host.clone(),
+                port,
+                proxy_id: Arc::new(Mutex::new(proxy_id)),
+                running: Arc::new(AtomicBool::new(true)),
+            };
+
+            let pool = self.pool.clone();
+            let warm = self.warm.clone();
+            let current_id = active.proxy_id.clone();
+            let rr_idx = Arc::new(AtomicUsize::new(0));
+            let no_auth = Arc::new(DashMap::new());
+            let context = move || ClientContext {
+                pool: pool.clone(),
+                mode: RotationMode::RoundRobin,
+                rr_idx: rr_idx.clone(),
+                auth_users: no_auth.clone(),
+                listen_port: port,
+                proxy_ids: vec![current_id.lock().unwrap().clone()],
+                fallback: Fallback::None,
+                failover_retries: 0,
+                hedge_delay: None,
+                listener_id: format!("port-map-{}", port),
+                rules: None,
+                group: None,
+                groups: Groups::default(),
+                warm: warm.clone(),
+            };
+            tauri::async_runtime::spawn(serve_listener(
+                format!("PortMap {}", port),
+                settings.host.clone(),
+                port,
+                InboundProtocol::Auto,
+                active.running.clone(),
+                context,
+            ));
+            self.active.insert(key, active);
+        }
+    }
+}
+
+/// Keeps the mapped ports in line with the pool as proxies come and go.
+pub fn start_port_mapper(port_map: PortMap) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            port_map.reconcile();
+            tokio::time::sleep(std::time::Duration::from_secs(RECONCILE_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(json: &str) -> PortMap {
+        let dir = std::env::temp_dir().join(format!("hydragate-portmap-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(PORT_MAP_FILE), json).unwrap();
+        let port_map = PortMap::new(ProxyPool::new(), Default::default());
+        port_map.load(dir.clone());
+        let _ = std::fs::remove_dir_all(dir);
+        port_map
+    }
+
+    #[test]
+    fn resets_ranges_that_overflow() {
+        let port_map = load(
+            r#"{"settings": {"enabled": true, "host": "0.0.0.0", "base_port": 65500, "size": 100},
+                "slots": {"a": 3, "b": 99, "c": 100}}"#,
+        );
+        let settings = port_map.get_settings();
+        assert_eq!(settings.host, "127.0.0.1");
+        assert_eq!((settings.base_port, settings.size), (20000, 100));
+        let slots = port_map.state.lock().unwrap().slots.clone();
+        assert_eq!(slots.keys().collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    #[test]
+    fn drops_slots_outside_the_range() {
+        let port_map = load(
+            r#"{"settings": {"enabled": true, "host": "127.0.0.1", "base_port": 30000, "size": 2},
+                "slots": {"a": 0, "b": 1, "c": 7}}"#,
+        );
+        assert_eq!(port_map.get_settings().base_port, 30000);
+        let slots = port_map.state.lock().unwrap().slots.clone();
+        assert_eq!(slots.keys().collect::<Vec<_>>(), ["a", "b"]);
+    }
+}
@@ -0,0 +1,593 @@
+use crate::engine::DEFAULT_LISTENER_ID;
+use crate::state::{DIRECT_ID, REJECT_ID};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+// Routing rules: an ordered list checked against each client connection's
+// target before an upstream is picked. The first enabled rule whose
+// conditions all hold decides where the connection goes; when none matches,
+// the listener's own proxy subset and rotation apply. Rules are persisted
+// next to the port map; pool proxies and added listeners are not, so rules
+// naming them by id are dropped on load.
+
+const RULES_FILE: &str = "rules.json";
+
+/// One test against a connection. Domain conditions never match IP-literal
+/// targets and `ip_cidr` never matches hostnames: targets are not resolved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Condition {
+    /// The whole hostname, case-insensitively.
+    Domain(String),
+    /// The hostname or any subdomain of it.
+    DomainSuffix(String),
+    /// Any hostname containing the keyword.
+    DomainKeyword(String),
+    /// A regular expression searched in the hostname.
+    DomainRegex(String),
+    /// An IP target inside the network, e.g. `10.0.0.0/8`.
+    IpCidr(String),
+    /// A destination port (`443`) or inclusive range (`8000-8999`).
+    Port(String),
+    /// The id of the listener the client connected to.
+    Listener(String),
+}
+
+/// Where a matching connection goes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// One pool proxy (or chain), by id.
+    Proxy(String),
+    /// Any alive proxy carrying the tag, picked by the listener's rotation.
+    Tag(String),
+    /// A proxy group, by id, picked by the group's own rotation.
+    Group(String),
+    Direct,
+    Reject,
+}
+
+impl RuleAction {
+    /// Id of the built-in upstream this action names, if any.
+    pub fn builtin_id(&self) -> Option<&str> {
+        match self {
+            RuleAction::Proxy(id) if id == DIRECT_ID || id == REJECT_ID => Some(id),
+            RuleAction::Direct => Some(DIRECT_ID),
+            RuleAction::Reject => Some(REJECT_ID),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    pub enabled: bool,
+    /// All must hold for the rule to match; none matches everything.
+    pub conditions: Vec<Condition>,
+    pub action: RuleAction,
+}
+
+impl Rule {
+    /// The first proxy or listener the rule names that only lives for one
+    /// session: everything but the built-in upstreams and the default
+    /// listener gets a new id on every start.
+    fn session_reference(&self) -> Option<String> {
+        if let RuleAction::Proxy(id) = &self.action {
+            if self.action.builtin_id().is_none() {
+                return Some(format!("proxy {}", id));
+            }
+        }
+        self.conditions.iter().find_map(|c| match c {
+            Condition::Listener(id) if id != DEFAULT_LISTENER_ID => {
+                Some(format!("listener {}", id))
+            }
+            _ => None,
+        })
+    }
+}
+
+/// A condition in the form it is evaluated in.
+enum Matcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    DomainRegex(Regex),
+    IpCidr(IpAddr, u8),
+    Port(u16, u16),
+    Listener(String),
+}
+
+impl Matcher {
+    fn compile(condition: &Condition) -> Result<Self, String> {
+        let domain = |value: &str| {
+            let value = value.trim().trim_end_matches('.').to_ascii_lowercase();
+            if value.is_empty() {
+                return Err("Domain conditions need a value".to_string());
+            }
+            Ok(value)
+        };
+        Ok(match condition {
+            Condition::Domain(v) => Matcher::Domain(domain(v)?),
+            Condition::DomainSuffix(v) => {
+                Matcher::DomainSuffix(domain(v.trim().trim_start_matches('.'))?)
+            }
+            Condition::DomainKeyword(v) => Matcher::DomainKeyword(domain(v)?),
+            Condition::DomainRegex(v) => Matcher::DomainRegex(
+                Regex::new(v).map_err(|e| format!("Invalid domain regex {:?}: {}", v, e))?,
+            ),
+            Condition::IpCidr(v) => {
+                let (ip, prefix) = parse_cidr(v).ok_or_else(|| format!("Invalid CIDR: {}", v))?;
+                Matcher::IpCidr(ip, prefix)
+            }
+            Condition::Port(v) => {
+                let (from, to) = parse_port_range(v)
+                    .ok_or_else(|| format!("Invalid port or port range: {}", v))?;
+                Matcher::Port(from, to)
+            }
+            Condition::Listener(v) => Matcher::Listener(v.clone()),
+        })
+    }
+
+    fn matches(&self, target: &Target) -> bool {
+        match self {
+            Matcher::Domain(d) => target.domain.as_deref() == Some(d.as_str()),
+            Matcher::DomainSuffix(d) => target.domain.as_deref().is_some_and(|host| {
+                host == d
+                    || host
+                        .strip_suffix(d.as_str())
+                        .is_some_and(|p| p.ends_with('.'))
+            }),
+            Matcher::DomainKeyword(k) => target.domain.as_deref().is_some_and(|h| h.contains(k)),
+            Matcher::DomainRegex(re) => target.domain.as_deref().is_some_and(|h| re.is_match(h)),
+            Matcher::IpCidr(net, prefix) => target.ip.is_some_and(|ip| in_cidr(ip, *net, *prefix)),
+            Matcher::Port(from, to) => (*from..=*to).contains(&target.port),
+            Matcher::Listener(id) => target.listener_id == Some(id.as_str()),
+        }
+    }
+}
+
+/// `a.b.c.d/n` or `v6::/n`; a bare address is a single host.
+fn parse_cidr(s: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match s.trim().split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+        None => (s.trim(), None),
+    };
+    let ip: IpAddr = addr.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    (prefix <= max).then_some((ip, prefix))
+}
+
+fn in_cidr(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip.to_canonical(), net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+fn parse_port_range(s: &str) -> Option<(u16, u16)> {
+    let (from, to) = match s.trim().split_once('-') {
+        Some((from, to)) => (from.trim().parse().ok()?, to.trim().parse().ok()?),
+        None => {
+            let port = s.trim().parse().ok()?;
+            (port, port)
+        }
+    };
+    (from <= to).then_some((from, to))
+}
+
+/// A connection as the rules see it.
+struct Target<'a> {
+    /// Lowercased hostname, when the target isn't an IP literal.
+    domain: Option<String>,
+    ip: Option<IpAddr>,
+    port: u16,
+    listener_id: Option<&'a str>,
+}
+
+impl<'a> Target<'a> {
+    fn new(listener_id: Option<&'a str>, host: &str, port: u16) -> Self {
+        let bare = host.trim_start_matches('[').trim_end_matches(']');
+        match bare.parse::<IpAddr>() {
+            Ok(ip) => Self {
+                domain: None,
+                ip: Some(ip),
+                port,
+                listener_id,
+            },
+            Err(_) => Self {
+                domain: Some(host.trim_end_matches('.').to_ascii_lowercase()),
+                ip: None,
+                port,
+                listener_id,
+            },
+        }
+    }
+}
+
+struct CompiledRule {
+    rule: Rule,
+    matchers: Vec<Matcher>,
+}
+
+impl CompiledRule {
+    fn new(rule: Rule) -> Result<Self, String> {
+        let matchers = rule
+            .conditions
+            .iter()
+            .map(Matcher::compile)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rule, matchers })
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Rules {
+    rules: Arc<RwLock<Vec<CompiledRule>>>,
+    storage: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl Rules {
+    /// Loads the persisted rules from `dir` and saves there from now on.
+    pub fn load(&self, dir: PathBuf) {
+        let path = dir.join(RULES_FILE);
+        match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<Vec<Rule>>(&bytes) {
+                Ok(rules) => {
+                    let total = rules.len();
+                    let compiled: Vec<CompiledRule> = rules
+                        .into_iter()
+                        .filter_map(|rule| {
+                            if let Some(gone) = rule.session_reference() {
+                                eprintln!(
+                                    "[Rules] Dropping rule '{}': {} is from an earlier session",
+                                    rule.name, gone
+                                );
+                                return None;
+                            }
+                            match CompiledRule::new(rule) {
+                                Ok(c) => Some(c),
+                                Err(e) => {
+                                    eprintln!("[Rules] Ignoring invalid rule: {}", e);
+                                    None
+                                }
+                            }
+                        })
+                        .collect();
+                    let dropped = compiled.len() < total;
+                    *self.rules.write().unwrap() = compiled;
+                    *self.storage.lock().unwrap() = Some(path);
+                    if dropped {
+                        self.save();
+                    }
+                    return;
+                }
+                Err(e) => eprintln!("[Rules] Ignoring corrupt {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("[Rules] Cannot read {}: {}", path.display(), e),
+        }
+        *self.storage.lock().unwrap() = Some(path);
+    }
+
+    fn save(&self) {
+        let Some(path) = self.storage.lock().unwrap().clone() else {
+            return;
+        };
+        let json = serde_json::to_vec_pretty(&self.list());
+        let result = json.map_err(std::io::Error::other).and_then(|bytes| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, bytes)
+        });
+        if let Err(e) = result {
+            eprintln!("[Rules] Cannot write {}: {}", path.display(), e);
+        }
+    }
+
+    /// All rules, in evaluation order.
+    pub fn list(&self) -> Vec<Rule> {
+        let rules = self.rules.read().unwrap();
+        rules.iter().map(|c| c.rule.clone()).collect()
+    }
+
+    pub fn add(&self, rule: Rule) -> Result<(), String> {
+        let compiled = CompiledRule::new(rule)?;
+        self.rules.write().unwrap().push(compiled);
+        self.save();
+        Ok(())
+    }
+
+    /// Replaces the rule with the same id in place. `Ok(false)` if there is
+    /// none.
+    pub fn update(&self, rule: Rule) -> Result<bool, String> {
+        let compiled = CompiledRule::new(rule)?;
+        {
+            let mut rules = self.rules.write().unwrap();
+            let Some(slot) = rules.iter_mut().find(|c| c.rule.id == compiled.rule.id) else {
+                return Ok(false);
+            };
+            *slot = compiled;
+        }
+        self.save();
+        Ok(true)
+    }
+
+    pub fn remove(&self, id: &str) -> bool {
+        let removed = {
+            let mut rules = self.rules.write().unwrap();
+            let before = rules.len();
+            rules.retain(|c| c.rule.id != id);
+            rules.len() != before
+        };
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Moves a rule to `index` in the evaluation order, clamped to the end.
+    pub fn move_to(&self, id: &str, index: usize) -> bool {
+        {
+            let mut rules = self.rules.write().unwrap();
+            let Some(from) = rules.iter().position(|c| c.rule.id == id) else {
+                return false;
+            };
+            let rule = rules.remove(from);
+            let index = index.min(rules.len());
+            rules.insert(index, rule);
+        }
+        self.save();
+        true
+    }
+
+    /// The first enabled rule matching a connection to `host:port` made
+    /// through `listener_id`.
+    pub fn find(&self, listener_id: Option<&str>, host: &str, port: u16) -> Option<Rule> {
+        let target = Target::new(listener_id, host, port);
+        let rules = self.rules.read().unwrap();
+        rules
+            .iter()
+            .filter(|c| c.rule.enabled)
+            .find(|c| c.matchers.iter().all(|m| m.matches(&target)))
+            .map(|c| c.rule.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(condition: Condition, host: &str, port: u16) -> bool {
+        Matcher::compile(&condition)
+            .unwrap()
+            .matches(&Target::new(Some("l1"), host, port))
+    }
+
+    fn rule(id: &str, enabled: bool, conditions: Vec<Condition>) -> Rule {
+        Rule {
+            id: id.to_string(),
+            name: id.to_string(),
+            enabled,
+            conditions,
+            action: RuleAction::Direct,
+        }
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(
+            parse_cidr("10.0.0.0/8"),
+            Some(("10.0.0.0".parse().unwrap(), 8))
+        );
+        assert_eq!(
+            parse_cidr(" 192.168.1.1 "),
+            Some(("192.168.1.1".parse().unwrap(), 32))
+        );
+        assert_eq!(parse_cidr("::1"), Some(("::1".parse().unwrap(), 128)));
+        assert_eq!(
+            parse_cidr("0.0.0.0/0"),
+            Some(("0.0.0.0".parse().unwrap(), 0))
+        );
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("fe80::/129"), None);
+        assert_eq!(parse_cidr("10.0.0.0/"), None);
+        assert_eq!(parse_cidr("example.com/8"), None);
+    }
+
+    #[test]
+    fn cidr_membership() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(in_cidr(ip("10.1.2.3"), ip("10.0.0.0"), 8));
+        assert!(!in_cidr(ip("11.0.0.1"), ip("10.0.0.0"), 8));
+        assert!(in_cidr(ip("203.0.113.9"), ip("0.0.0.0"), 0));
+        assert!(in_cidr(ip("2001:db8::1"), ip("::"), 0));
+        assert!(in_cidr(ip("192.168.1.1"), ip("192.168.1.1"), 32));
+        assert!(!in_cidr(ip("192.168.1.2"), ip("192.168.1.1"), 32));
+        assert!(in_cidr(ip("2001:db8::1"), ip("2001:db8::"), 32));
+        assert!(!in_cidr(ip("2001:db9::1"), ip("2001:db8::"), 32));
+        // IPv4-mapped IPv6 addresses count as their IPv4 address.
+        assert!(in_cidr(ip("::ffff:10.1.2.3"), ip("10.0.0.0"), 8));
+        // Families never match each other.
+        assert!(!in_cidr(ip("10.1.2.3"), ip("::"), 0));
+        assert!(!in_cidr(ip("2001:db8::1"), ip("0.0.0.0"), 0));
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(parse_port_range("443"), Some((443, 443)));
+        assert_eq!(parse_port_range(" 8000 - 8999 "), Some((8000, 8999)));
+        assert_eq!(parse_port_range("0-65535"), Some((0, 65535)));
+        assert_eq!(parse_port_range("9000-8000"), None);
+        assert_eq!(parse_port_range("65536"), None);
+        assert_eq!(parse_port_range("http"), None);
+        assert_eq!(parse_port_range("80-"), None);
+    }
+
+    #[test]
+    fn domain_suffix_matches_whole_labels() {
+        let suffix = || Condition::DomainSuffix("example.com".to_string());
+        assert!(matches(suffix(), "example.com", 443));
+        assert!(matches(suffix(), "www.Example.COM", 443));
+        assert!(matches(suffix(), "www.example.com.", 443));
+        assert!(!matches(suffix(), "badexample.com", 443));
+        assert!(!matches(suffix(), "example.com.evil.net", 443));
+        // A leading or trailing dot in the condition is ignored.
+        assert!(matches(
+            Condition::DomainSuffix(".example.com.".to_string()),
+            "a.example.com",
+            443
+        ));
+    }
+
+    #[test]
+    fn domain_conditions() {
+        let domain = || Condition::Domain("Example.com.".to_string());
+        assert!(matches(domain(), "example.com", 80));
+        assert!(matches(domain(), "EXAMPLE.com.", 80));
+        assert!(!matches(domain(), "www.example.com", 80));
+        assert!(matches(
+            Condition::DomainKeyword("tracker".to_string()),
+            "ads.tracker.net",
+            80
+        ));
+        assert!(matches(
+            Condition::DomainRegex(r"^cdn\d+\.".to_string()),
+            "cdn12.example.net",
+            80
+        ));
+        assert!(!matches(
+            Condition::DomainRegex(r"^cdn\d+\.".to_string()),
+            "static.cdn12.example.net",
+            80
+        ));
+        assert!(Matcher::compile(&Condition::DomainRegex("(".to_string())).is_err());
+        assert!(Matcher::compile(&Condition::Domain(" . ".to_string())).is_err());
+    }
+
+    #[test]
+    fn domain_and_ip_conditions_never_cross() {
+        let cidr = || Condition::IpCidr("127.0.0.0/8".to_string());
+        assert!(matches(cidr(), "127.0.0.1", 80));
+        assert!(!matches(cidr(), "localhost", 80));
+        assert!(!matches(
+            Condition::DomainKeyword("127".to_string()),
+            "127.0.0.1",
+            80
+        ));
+        // Bracketed IPv6 targets are IPs too.
+        assert!(matches(Condition::IpCidr("::1".to_string()), "[::1]", 80));
+    }
+
+    #[test]
+    fn port_and_listener_conditions() {
+        assert!(matches(Condition::Port("8000-8999".to_string()), "a", 8080));
+        assert!(!matches(
+            Condition::Port("8000-8999".to_string()),
+            "a",
+            9000
+        ));
+        assert!(matches(Condition::Listener("l1".to_string()), "a", 80));
+        assert!(!matches(Condition::Listener("l2".to_string()), "a", 80));
+        assert!(Matcher::compile(&Condition::Port("9000-8000".to_string())).is_err());
+    }
+
+    #[test]
+    fn first_enabled_matching_rule_wins() {
+        let rules = Rules::default();
+        rules
+            .add(rule(
+                "disabled",
+                false,
+                vec![Condition::Domain("example.com".to_string())],
+            ))
+            .unwrap();
+        rules
+            .add(rule(
+                "web",
+                true,
+                vec![
+                    Condition::DomainSuffix("example.com".to_string()),
+                    Condition::Port("443".to_string()),
+                ],
+            ))
+            .unwrap();
+        rules.add(rule("catch-all", true, Vec::new())).unwrap();
+
+        let find = |host: &str, port: u16| rules.find(None, host, port).map(|r| r.id);
+        assert_eq!(find("example.com", 443).as_deref(), Some("web"));
+        // Conditions are ANDed.
+        assert_eq!(find("example.com", 80).as_deref(), Some("catch-all"));
+
+        assert!(rules.move_to("catch-all", 0));
+        assert_eq!(find("example.com", 443).as_deref(), Some("catch-all"));
+        assert!(rules.remove("catch-all"));
+        assert_eq!(find("example.com", 80), None);
+    }
+
+    #[test]
+    fn drops_rules_naming_session_ids_on_load() {
+        let dir = std::env::temp_dir().join(format!("hydragate-rules-{}", uuid::Uuid::new_v4()));
+        let mut to_proxy = rule("to-proxy", true, Vec::new());
+        to_proxy.action = RuleAction::Proxy("3f2a".to_string());
+        let mut to_direct = rule("to-direct", true, Vec::new());
+        to_direct.action = RuleAction::Proxy(DIRECT_ID.to_string());
+        let saved = Rules::default();
+        saved.load(dir.clone());
+        for r in [
+            to_proxy,
+            to_direct,
+            rule(
+                "on-added",
+                true,
+                vec![Condition::Listener("9c1d".to_string())],
+            ),
+            rule(
+                "on-default",
+                true,
+                vec![Condition::Listener(DEFAULT_LISTENER_ID.to_string())],
+            ),
+        ] {
+            saved.add(r).unwrap();
+        }
+
+        let loaded = Rules::default();
+        loaded.load(dir.clone());
+        let ids: Vec<String> = loaded.list().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, ["to-direct", "on-default"]);
+        // The file no longer carries them either.
+        let reloaded = Rules::default();
+        reloaded.load(dir.clone());
+        assert_eq!(reloaded.list().len(), 2);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn invalid_rules_are_refused() {
+        let rules = Rules::default();
+        let bad = rule(
+            "bad",
+            true,
+            vec![Condition::IpCidr("10.0.0.0/40".to_string())],
+        );
+        assert!(rules.add(bad.clone()).is_err());
+        assert!(rules.list().is_empty());
+        assert_eq!(
+            rules.update(bad),
+            Err("Invalid CIDR: 10.0.0.0/40".to_string())
+        );
+    }
+}
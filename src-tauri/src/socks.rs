@@ -0,0 +1,343 @@
+use crate::error::UpstreamError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_BIND: u8 = 0x02;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+// ─── Address encoding ───────────────────────────────────────────────────────
+
+/// Encodes `ATYP | ADDR | PORT`. IP literals use the IPv4/IPv6 forms, anything
+/// else is sent as a domain name.
+pub fn encode_addr(host: &str, port: u16) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(host.len() + 4);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.is_empty() || host.len() > 255 {
+                return Err(invalid_data(format!("Invalid SOCKS5 hostname: {}", host)));
+            }
+            out.push(ATYP_DOMAIN);
+            out.push(host.len() as u8);
+            out.extend_from_slice(host.as_bytes());
+        }
+    }
+    out.extend_from_slice(&port.to_be_bytes());
+    Ok(out)
+}
+
+/// Reads `ADDR | PORT` for an already-consumed `ATYP` byte.
+pub async fn read_addr<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    atyp: u8,
+) -> std::io::Result<(String, u16)> {
+    let mut buf = [0u8; 256];
+    let host = match atyp {
+        ATYP_IPV4 => {
+            stream.read_exact(&mut buf[0..4]).await?;
+            Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]).to_string()
+        }
+        ATYP_DOMAIN => {
+            stream.read_exact(&mut buf[0..1]).await?;
+            let len = buf[0] as usize;
+            stream.read_exact(&mut buf[0..len]).await?;
+            String::from_utf8_lossy(&buf[0..len]).into_owned()
+        }
+        ATYP_IPV6 => {
+            let mut arr = [0u8; 16];
+            stream.read_exact(&mut arr).await?;
+            Ipv6Addr::from(arr).to_string()
+        }
+        _ => return Err(invalid_data("Invalid address type".to_string())),
+    };
+    stream.read_exact(&mut buf[0..2]).await?;
+    Ok((host, u16::from_be_bytes([buf[0], buf[1]])))
+}
+
+/// Builds a server reply `VER | REP | RSV | ATYP | BND.ADDR | BND.PORT`.
+/// Without a bound address, `0.0.0.0:0` is reported.
+pub fn reply(code: u8, bound: Option<SocketAddr>) -> Vec<u8> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    reply_to(code, &bound.ip().to_string(), bound.port())
+}
+
+/// Like [`reply`], for a bound address that may be a hostname.
+pub fn reply_to(code: u8, host: &str, port: u16) -> Vec<u8> {
+    let mut out = vec![0x05, code, 0x00];
+    match encode_addr(host, port) {
+        Ok(addr) => out.extend(addr),
+        Err(_) => out.extend_from_slice(&[ATYP_IPV4, 0, 0, 0, 0, 0, 0]),
+    }
+    out
+}
+
+/// Servers commonly answer `0.0.0.0` to mean "the address you reached me
+/// on"; substitute that address so clients get something routable.
+pub fn resolve_unspecified(host: String, peer: IpAddr) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => peer.to_string(),
+        _ => host,
+    }
+}
+
+// ─── Upstream client ────────────────────────────────────────────────────────
+
+/// Method negotiation plus optional RFC 1929 authentication against an
+/// upstream SOCKS5 server.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    user: Option<&str>,
+    pass: Option<&str>,
+) -> Result<(), UpstreamError> {
+    let creds = user.zip(pass);
+    if creds.is_some() {
+        stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await?;
+    } else {
+        stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    }
+
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != 0x05 {
+        return Err(UpstreamError::Protocol(
+            "Upstream is not a SOCKS5 server".to_string(),
+        ));
+    }
+
+    match (buf[1], creds) {
+        (0x00, _) => Ok(()),
+        (0x02, Some((u, p))) => {
+            if u.len() > 255 || p.len() > 255 {
+                return Err(UpstreamError::Protocol(
+                    "Upstream credentials too long".to_string(),
+                ));
+            }
+            let mut req = vec![0x01, u.len() as u8];
+            req.extend_from_slice(u.as_bytes());
+            req.push(p.len() as u8);
+            req.extend_from_slice(p.as_bytes());
+            stream.write_all(&req).await?;
+
+            stream.read_exact(&mut buf).await?;
+            if buf[1] != 0x00 {
+                return Err(UpstreamError::AuthRejected);
+            }
+            Ok(())
+        }
+        _ => Err(UpstreamError::AuthRejected),
+    }
+}
+
+/// Reads one server reply and returns `BND.ADDR:BND.PORT`.
+pub async fn read_reply<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<(String, u16), UpstreamError> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 0x05 {
+        return Err(UpstreamError::Protocol("Invalid SOCKS5 reply".to_string()));
+    }
+    // Some servers close right after a failure reply; the code is what counts.
+    if head[1] != REPLY_SUCCEEDED {
+        return Err(UpstreamError::SocksReply(head[1]));
+    }
+    Ok(read_addr(stream, head[3]).await?)
+}
+
+/// Sends a command (`CONNECT`, `BIND`, `UDP ASSOCIATE`) and reads the first
+/// reply.
+pub async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    command: u8,
+    host: &str,
+    port: u16,
+) -> Result<(String, u16), UpstreamError> {
+    let mut req = vec![0x05, command, 0x00];
+    req.extend(encode_addr(host, port)?);
+    stream.write_all(&req).await?;
+    read_reply(stream).await
+}
+
+// ─── Upstream SOCKS4 client ─────────────────────────────────────────────────
+
+const SOCKS4_GRANTED: u8 = 0x5A;
+
+/// SOCKS4 `CONNECT`. With `remote_dns` (SOCKS4a) hostnames are passed to the
+/// proxy to resolve; otherwise they are resolved here, since plain SOCKS4
+/// only carries an IPv4 address. `user_id` fills the USERID field.
+pub async fn connect_v4<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    user_id: Option<&str>,
+    remote_dns: bool,
+) -> Result<(String, u16), UpstreamError> {
+    let mut hostname: Option<&str> = None;
+    let ip = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip,
+        Ok(IpAddr::V6(_)) => {
+            return Err(UpstreamError::SocksReply(REPLY_ADDRESS_TYPE_NOT_SUPPORTED));
+        }
+        Err(_) if remote_dns => {
+            if host.is_empty() || host.len() > 255 || host.contains('\0') {
+                return Err(invalid_data(format!("Invalid SOCKS4a hostname: {}", host)).into());
+            }
+            hostname = Some(host);
+            // 0.0.0.x (x != 0) tells the proxy a hostname follows.
+            Ipv4Addr::new(0, 0, 0, 1)
+        }
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| UpstreamError::SocksReply(REPLY_HOST_UNREACHABLE))?
+            .find_map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .ok_or(UpstreamError::SocksReply(REPLY_HOST_UNREACHABLE))?,
+    };
+
+    let user_id = user_id.unwrap_or("");
+    if user_id.len() > 255 || user_id.contains('\0') {
+        return Err(UpstreamError::Protocol(
+            "Invalid SOCKS4 user id".to_string(),
+        ));
+    }
+
+    let mut req = vec![0x04, CMD_CONNECT];
+    req.extend_from_slice(&port.to_be_bytes());
+    req.extend_from_slice(&ip.octets());
+    req.extend_from_slice(user_id.as_bytes());
+    req.push(0);
+    if let Some(name) = hostname {
+        req.extend_from_slice(name.as_bytes());
+        req.push(0);
+    }
+    stream.write_all(&req).await?;
+
+    let mut resp = [0u8; 8];
+    stream.read_exact(&mut resp).await?;
+    if resp[0] != 0x00 {
+        return Err(UpstreamError::Protocol("Invalid SOCKS4 reply".to_string()));
+    }
+    if resp[1] != SOCKS4_GRANTED {
+        return Err(UpstreamError::Socks4Rejected(resp[1]));
+    }
+    let bound_ip = Ipv4Addr::new(resp[4], resp[5], resp[6], resp[7]);
+    Ok((bound_ip.to_string(), u16::from_be_bytes([resp[2], resp[3]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(host: &str, port: u16) -> (String, u16) {
+        let encoded = encode_addr(host, port).unwrap();
+        let mut rest = &encoded[1..];
+        read_addr(&mut rest, encoded[0]).await.unwrap()
+    }
+
+    #[test]
+    fn encodes_addresses() {
+        assert_eq!(
+            encode_addr("10.0.0.1", 443).unwrap(),
+            [ATYP_IPV4, 10, 0, 0, 1, 0x01, 0xbb]
+        );
+        let v6 = encode_addr("2001:db8::1", 80).unwrap();
+        assert_eq!(v6[0], ATYP_IPV6);
+        assert_eq!(v6.len(), 1 + 16 + 2);
+        assert_eq!(
+            encode_addr("a.io", 8080).unwrap(),
+            [ATYP_DOMAIN, 4, b'a', b'.', b'i', b'o', 0x1f, 0x90]
+        );
+        assert!(encode_addr("", 80).is_err());
+        assert!(encode_addr(&"a".repeat(256), 80).is_err());
+        assert!(encode_addr(&"a".repeat(255), 80).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reads_back_encoded_addresses() {
+        assert_eq!(round_trip("192.0.2.7", 53).await, ("192.0.2.7".into(), 53));
+        assert_eq!(
+            round_trip("2001:db8::1", 443).await,
+            ("2001:db8::1".into(), 443)
+        );
+        assert_eq!(
+            round_trip("example.com", 0).await,
+            ("example.com".into(), 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_and_truncated_addresses() {
+        let mut short: &[u8] = &[10, 0];
+        assert!(read_addr(&mut short, ATYP_IPV4).await.is_err());
+        let mut any: &[u8] = &[0; 8];
+        let err = read_addr(&mut any, 0x02).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn builds_replies_with_bound_addresses() {
+        assert_eq!(
+            reply(REPLY_SUCCEEDED, None),
+            [0x05, 0x00, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            reply(
+                REPLY_SUCCEEDED,
+                Some(SocketAddr::from(([192, 0, 2, 1], 1080)))
+            ),
+            [0x05, 0x00, 0x00, ATYP_IPV4, 192, 0, 2, 1, 0x04, 0x38]
+        );
+        assert_eq!(
+            reply_to(REPLY_SUCCEEDED, "bnd.example", 1)[3..5],
+            [ATYP_DOMAIN, 11]
+        );
+        // An unencodable bound host still yields a well-formed reply.
+        assert_eq!(
+            reply_to(REPLY_HOST_UNREACHABLE, "", 1),
+            [0x05, 0x04, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn substitutes_unspecified_bound_addresses() {
+        let peer: IpAddr = "203.0.113.5".parse().unwrap();
+        assert_eq!(resolve_unspecified("0.0.0.0".into(), peer), "203.0.113.5");
+        assert_eq!(resolve_unspecified("::".into(), peer), "203.0.113.5");
+        assert_eq!(
+            resolve_unspecified("198.51.100.1".into(), peer),
+            "198.51.100.1"
+        );
+        assert_eq!(
+            resolve_unspecified("bnd.example".into(), peer),
+            "bnd.example"
+        );
+    }
+}
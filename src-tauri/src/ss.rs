@@ -0,0 +1,138 @@
+use crate::engine::UpstreamStream;
+use crate::error::UpstreamError;
+use crate::state::Proxy;
+use shadowsocks::config::{ServerConfig, ServerType};
+use shadowsocks::context::{Context, SharedContext};
+use shadowsocks::crypto::CipherKind;
+use shadowsocks::relay::Address;
+use shadowsocks::ProxyClientStream;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
+// Shadowsocks upstreams (`ss`). The cipher comes from `Proxy.cipher`, falling
+// back to `user` so `ss://host:port:method:password` imports work as-is; the
+// password is `Proxy.pass`.
+
+/// Parses a cipher name such as `aes-256-gcm`, `chacha20-ietf-poly1305` or
+/// `2022-blake3-aes-256-gcm`. Only AEAD ciphers are accepted.
+pub fn parse_cipher(name: &str) -> Option<CipherKind> {
+    let kind = name.parse::<CipherKind>().ok()?;
+    (kind.is_aead() || kind.is_aead_2022()).then_some(kind)
+}
+
+fn context() -> SharedContext {
+    static CONTEXT: OnceLock<SharedContext> = OnceLock::new();
+    CONTEXT
+        .get_or_init(|| Context::new_shared(ServerType::Local))
+        .clone()
+}
+
+fn server_config(selected: &Proxy) -> Result<ServerConfig, UpstreamError> {
+    let name = selected
+        .cipher
+        .as_deref()
+        .or(selected.user.as_deref())
+        .ok_or_else(|| UpstreamError::Protocol("Shadowsocks proxy has no cipher".to_string()))?;
+    let method = parse_cipher(name)
+        .ok_or_else(|| UpstreamError::Protocol(format!("Unknown Shadowsocks cipher {}", name)))?;
+    let password = selected.pass.clone().unwrap_or_default();
+    ServerConfig::new((selected.host.clone(), selected.port), password, method)
+        .map_err(|e| UpstreamError::Protocol(format!("Invalid Shadowsocks key: {}", e)))
+}
+
+/// Wraps an open connection to the Shadowsocks server. The target address
+/// is sent with the first payload, so the server cannot refuse it up front:
+/// a bad target or password only shows up as the stream closing.
+pub(crate) fn connect(
+    stream: UpstreamStream,
+    selected: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<UpstreamStream, UpstreamError> {
+    let config = server_config(selected)?;
+    let target = match target_host.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, target_port)),
+        Err(_) => Address::DomainNameAddress(target_host.to_string(), target_port),
+    };
+    Ok(Box::new(ProxyClientStream::from_stream(
+        context(),
+        stream,
+        &config,
+        target,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shadowsocks::relay::tcprelay::proxy_listener::ProxyListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn relays_through_in_process_server() {
+        let method = "chacha20-ietf-poly1305";
+        let password = "secret";
+        let server_config = ServerConfig::new(
+            ("127.0.0.1".to_string(), 0),
+            password,
+            parse_cipher(method).unwrap(),
+        )
+        .unwrap();
+        let listener = ProxyListener::bind(Context::new_shared(ServerType::Server), &server_config)
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let target = stream.handshake().await.unwrap();
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            stream.flush().await.unwrap();
+            (target, ping)
+        });
+
+        let proxy = Proxy {
+            protocol: "ss".to_string(),
+            host: "127.0.0.1".to_string(),
+            port,
+            pass: Some(password.to_string()),
+            cipher: Some(method.to_string()),
+            ..Default::default()
+        };
+        let tcp = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut stream = connect(Box::new(tcp), &proxy, "example.com", 443).unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+
+        let (target, ping) = server.await.unwrap();
+        assert_eq!(&pong, b"pong");
+        assert_eq!(&ping, b"ping");
+        assert_eq!(
+            target,
+            Address::DomainNameAddress("example.com".to_string(), 443)
+        );
+    }
+
+    #[test]
+    fn cipher_falls_back_to_user() {
+        let proxy = Proxy {
+            protocol: "ss".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 8388,
+            user: Some("aes-256-gcm".to_string()),
+            pass: Some("secret".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            server_config(&proxy).unwrap().method(),
+            CipherKind::AES_256_GCM
+        );
+        assert!(parse_cipher("rc4-md5").is_none());
+        assert!(parse_cipher("nonsense").is_none());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::engine::{connect_proxy, UpstreamStream};
+use crate::error::UpstreamError;
+use crate::socks;
+use crate::state::Proxy;
+use dashmap::DashMap;
+use russh::client::{self, AuthResult, Handle};
+use russh::keys::{HashAlg, PrivateKeyWithHashAlg, PublicKeyOrCertificate};
+use russh::ChannelOpenFailure;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Mutex;
+
+// SSH upstreams (`ssh`). One multiplexed session is kept per proxy and every
+// client connection becomes a `direct-tcpip` channel on it. Authentication
+// uses `Proxy.private_key` (PEM text or a file path, with `pass` as its
+// passphrase) when set, the `pass` password otherwise.
+
+type Session = Arc<Handle<HostKeyCheck>>;
+
+/// Open sessions by proxy endpoint and credentials. Each slot has its own
+/// lock so only one handshake runs per proxy while others are unaffected.
+fn sessions() -> &'static DashMap<String, Arc<Mutex<Option<Session>>>> {
+    static SESSIONS: OnceLock<DashMap<String, Arc<Mutex<Option<Session>>>>> = OnceLock::new();
+    SESSIONS.get_or_init(DashMap::new)
+}
+
+/// Host key fingerprints seen so far, for proxies without a pinned key.
+fn known_hosts() -> &'static DashMap<String, String> {
+    static KNOWN: OnceLock<DashMap<String, String>> = OnceLock::new();
+    KNOWN.get_or_init(DashMap::new)
+}
+
+/// Covers everything the handshake checks, so a proxy whose credentials or
+/// pinned host key changed gets a new session instead of the old one, and
+/// two proxies for the same account with different keys never share one.
+fn session_key(p: &Proxy) -> String {
+    format!(
+        "{}:{}@{}\n{}\n{}",
+        p.user.as_deref().unwrap_or(""),
+        p.pass.as_deref().unwrap_or(""),
+        crate::engine::join_host_port(&p.host, p.port),
+        p.private_key.as_deref().unwrap_or(""),
+        p.host_key.as_deref().unwrap_or("")
+    )
+}
+
+/// Closes the session opened with `p`'s settings, after the proxy was edited
+/// or removed. Channels already open on it are closed too.
+pub(crate) fn close_session(p: &Proxy) {
+    if !p.is_ssh() {
+        return;
+    }
+    if let Some((_, slot)) = sessions().remove(&session_key(p)) {
+        tauri::async_runtime::spawn(async move {
+            if let Some(handle) = slot.lock().await.take() {
+                let _ = handle
+                    .disconnect(russh::Disconnect::ByApplication, "", "")
+                    .await;
+            }
+        });
+    }
+}
+
+/// Accepts the server when its key matches `Proxy.host_key` (a `SHA256:...`
+/// fingerprint) or, without one, the key first seen for that address.
+struct HostKeyCheck {
+    address: String,
+    pinned: Option<String>,
+}
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let fingerprint = server_public_key
+            .public_key()
+            .fingerprint(HashAlg::Sha256)
+            .to_string();
+        if let Some(pinned) = &self.pinned {
+            return Ok(*pinned == fingerprint);
+        }
+        let known = known_hosts()
+            .entry(self.address.clone())
+            .or_insert_with(|| fingerprint.clone());
+        if *known != fingerprint {
+            eprintln!(
+                "[SSH] Host key for {} changed (expected {}, got {})",
+                self.address,
+                known.value(),
+                fingerprint
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+fn ssh_error(e: russh::Error) -> UpstreamError {
+    match e {
+        russh::Error::IO(e) => UpstreamError::Io(e),
+        russh::Error::UnknownKey => UpstreamError::Protocol("SSH host key not trusted".to_string()),
+        russh::Error::ChannelOpenFailure(reason) => match reason {
+            ChannelOpenFailure::AdministrativelyProhibited => {
+                UpstreamError::SocksReply(socks::REPLY_NOT_ALLOWED)
+            }
+            ChannelOpenFailure::ConnectFailed => {
+                UpstreamError::SocksReply(socks::REPLY_HOST_UNREACHABLE)
+            }
+            other => UpstreamError::Protocol(format!("SSH channel refused: {:?}", other)),
+        },
+        other => UpstreamError::Protocol(format!("SSH: {}", other)),
+    }
+}
+
+async fn authenticate(handle: &mut Handle<HostKeyCheck>, p: &Proxy) -> Result<(), UpstreamError> {
+    let user = p
+        .user
+        .clone()
+        .ok_or_else(|| UpstreamError::Protocol("SSH proxies need a username".to_string()))?;
+
+    let result: AuthResult = match p.private_key.as_deref() {
+        Some(key) => {
+            let passphrase = p.pass.as_deref();
+            let key = if key.trim_start().starts_with("-----BEGIN") {
+                russh::keys::decode_secret_key(key, passphrase)
+            } else {
+                russh::keys::load_secret_key(key, passphrase)
+            }
+            .map_err(|e| UpstreamError::Protocol(format!("Cannot load SSH key: {}", e)))?;
+            let hash_alg = handle
+                .best_supported_rsa_hash()
+                .await
+                .map_err(ssh_error)?
+                .flatten();
+            handle
+                .authenticate_publickey(user, PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg))
+                .await
+                .map_err(ssh_error)?
+        }
+        None => handle
+            .authenticate_password(user, p.pass.clone().unwrap_or_default())
+            .await
+            .map_err(ssh_error)?,
+    };
+
+    if !result.success() {
+        return Err(UpstreamError::AuthRejected);
+    }
+    Ok(())
+}
+
+async fn open_session(p: &Proxy) -> Result<Session, UpstreamError> {
+    let config = Arc::new(client::Config {
+        keepalive_interval: Some(std::time::Duration::from_secs(15)),
+        keepalive_max: 3,
+        ..Default::default()
+    });
+    let handler = HostKeyCheck {
+        address: crate::engine::join_host_port(&p.host, p.port),
+        pinned: p.host_key.clone(),
+    };
+    let stream = connect_proxy(p).await?;
+    let mut handle = client::connect_stream(config, stream, handler)
+        .await
+        .map_err(ssh_error)?;
+    authenticate(&mut handle, p).await?;
+    println!(
+        "[SSH] Session established to {}@{}",
+        p.user.as_deref().unwrap_or(""),
+        crate::engine::join_host_port(&p.host, p.port)
+    );
+    Ok(Arc::new(handle))
+}
+
+/// The live session for `p`, opening a new one if there is none or the last
+/// one has closed.
+async fn session(p: &Proxy) -> Result<Session, UpstreamError> {
+    let slot = sessions()
+        .entry(session_key(p))
+        .or_insert_with(|| Arc::new(Mutex::new(None)))
+        .clone();
+    let mut slot = slot.lock().await;
+    if let Some(handle) = slot.as_ref() {
+        if !handle.is_closed() {
+            return Ok(handle.clone());
+        }
+    }
+    *slot = None;
+    let handle = open_session(p).await?;
+    *slot = Some(handle.clone());
+    Ok(handle)
+}
+
+/// Opens a `direct-tcpip` channel to the target over the proxy's session.
+pub(crate) async fn connect(
+    p: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<UpstreamStream, UpstreamError> {
+    let handle = session(p).await?;
+    let channel = handle
+        .channel_open_direct_tcpip(target_host, target_port as u32, "127.0.0.1", 0)
+        .await
+        .map_err(ssh_error)?;
+    Ok(Box::new(channel.into_stream()))
+}
+
+/// Health check: the proxy is alive while its session is up and answers a
+/// ping.
+pub(crate) async fn check(p: &Proxy) -> Result<(), UpstreamError> {
+    let handle = session(p).await?;
+    handle.send_ping().await.map_err(ssh_error)
+}
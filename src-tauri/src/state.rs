@@ -1,8 +1,15 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Id of the built-in upstream that connects from this machine.
+pub const DIRECT_ID: &str = "direct";
+/// Id of the built-in upstream that refuses every connection.
+pub const REJECT_ID: &str = "reject";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Proxy {
     pub id: String,
     pub protocol: String,
@@ -12,20 +19,196 @@ pub struct Proxy {
     pub pass: Option<String>,
     pub latency_ms: Option<u64>,
     pub is_alive: bool,
+    /// `https` only: accept any certificate from the proxy.
+    #[serde(default)]
+    pub skip_tls_verify: bool,
+    /// `ss` only: the AEAD cipher, e.g. `chacha20-ietf-poly1305`.
+    #[serde(default)]
+    pub cipher: Option<String>,
+    /// `ssh` only: private key as PEM text or a file path; `pass` is its
+    /// passphrase. Without it `pass` is the login password.
+    #[serde(default)]
+    pub private_key: Option<String>,
+    /// `ssh` only: expected host key fingerprint (`SHA256:...`).
+    #[serde(default)]
+    pub host_key: Option<String>,
+    /// `chain` only: pool ids of the hops, entry first. `host` holds the
+    /// chain's name.
+    #[serde(default)]
+    pub hops: Vec<String>,
+    /// `http`/`https` only: extra headers sent with each `CONNECT`. Values
+    /// may use `{target_host}`, `{target_port}`, `{target}` and
+    /// `{session_id}` (random per connection).
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Free-form labels that routing rules can select proxies by.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The address traffic leaves from, as seen by the health check.
+    #[serde(default)]
+    pub exit_ip: Option<String>,
+    /// What `host` resolved to when the proxy was last located, for proxies
+    /// given by hostname whose exit address is unknown.
+    #[serde(default)]
+    pub host_ip: Option<String>,
+    /// GeoIP data for `exit_ip`, or for the host's address until that is
+    /// known.
+    #[serde(default)]
+    pub geo: Option<GeoInfo>,
+}
+
+/// Where an address is, according to the local GeoIP databases.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 code, e.g. `DE`.
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+}
+
+/// A username/password pair accepted by the local SOCKS5 listener (RFC 1929).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthUser {
+    pub username: String,
+    pub password: String,
+}
+
+impl Proxy {
+    /// The built-in `direct` or `reject` upstream, if `id` names one. They
+    /// live outside the pool and are always available.
+    pub fn builtin(id: &str) -> Option<Proxy> {
+        matches!(id, DIRECT_ID | REJECT_ID).then(|| Proxy {
+            id: id.to_string(),
+            protocol: id.to_string(),
+            is_alive: true,
+            ..Default::default()
+        })
+    }
+
+    /// Whether this is the built-in upstream that bypasses proxies.
+    pub fn is_direct(&self) -> bool {
+        self.protocol == DIRECT_ID
+    }
+
+    /// Whether this is the built-in upstream that refuses connections.
+    pub fn is_reject(&self) -> bool {
+        self.protocol == REJECT_ID
+    }
+
+    /// Whether this upstream speaks HTTP (plain `CONNECT` / absolute-form).
+    pub fn is_http(&self) -> bool {
+        self.protocol == "http" || self.protocol == "https"
+    }
+
+    /// Whether the connection to the proxy itself is wrapped in TLS.
+    pub fn uses_tls(&self) -> bool {
+        self.protocol == "https"
+    }
+
+    /// Whether this upstream speaks SOCKS4: `socks4` resolves hostnames
+    /// locally, `socks4a` lets the proxy resolve them.
+    pub fn is_socks4(&self) -> bool {
+        self.protocol == "socks4" || self.protocol == "socks4a"
+    }
+
+    /// Whether this upstream is a Shadowsocks server.
+    pub fn is_shadowsocks(&self) -> bool {
+        self.protocol == "ss"
+    }
+
+    /// Whether this upstream is an SSH server reached via `direct-tcpip`.
+    pub fn is_ssh(&self) -> bool {
+        self.protocol == "ssh"
+    }
+
+    /// Whether this upstream speaks SOCKS5, the only one BIND and UDP
+    /// ASSOCIATE can be relayed through.
+    pub fn is_socks5(&self) -> bool {
+        self.protocol == "socks5"
+    }
+
+    /// Whether the proxy carries `tag`, either set by the user or derived
+    /// from its GeoIP data as `country:<code>` or `asn:<number>`.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        if self.tags.iter().any(|t| t == tag) {
+            return true;
+        }
+        let Some(geo) = &self.geo else {
+            return false;
+        };
+        match tag.split_once(':') {
+            Some(("country", code)) => geo
+                .country
+                .as_deref()
+                .is_some_and(|c| c.eq_ignore_ascii_case(code)),
+            Some(("asn", number)) => geo.asn.is_some_and(|asn| number.parse() == Ok(asn)),
+            _ => false,
+        }
+    }
+
+    /// Whether this is a chain of other pool proxies rather than a server.
+    pub fn is_chain(&self) -> bool {
+        self.protocol == "chain"
+    }
+
+    /// Whether UDP ASSOCIATE can be relayed through this upstream.
+    pub fn supports_udp(&self) -> bool {
+        self.is_socks5()
+    }
 }
 
 #[derive(Clone)]
 pub struct ProxyPool {
     pub proxies: Arc<DashMap<String, Proxy>>,
+    /// Passive health: proxies that just failed real traffic, by id.
+    penalties: Arc<DashMap<String, Penalty>>,
+}
+
+/// First penalty for a failing proxy; each further failure doubles it.
+const PENALTY_BASE: Duration = Duration::from_secs(10);
+const PENALTY_MAX: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy)]
+struct Penalty {
+    strikes: u32,
+    until: Instant,
 }
 
 impl ProxyPool {
     pub fn new() -> Self {
         Self {
             proxies: Arc::new(DashMap::new()),
+            penalties: Arc::new(DashMap::new()),
         }
     }
 
+    /// Sidelines a proxy that failed a client connection, for longer on
+    /// each consecutive failure. Returns how long.
+    pub fn penalize(&self, id: &str) -> Duration {
+        let mut penalty = self.penalties.entry(id.to_string()).or_insert(Penalty {
+            strikes: 0,
+            until: Instant::now(),
+        });
+        penalty.strikes += 1;
+        let cooldown = PENALTY_BASE
+            .saturating_mul(1 << (penalty.strikes - 1).min(16))
+            .min(PENALTY_MAX);
+        penalty.until = Instant::now() + cooldown;
+        cooldown
+    }
+
+    /// Forgets past failures, after a success.
+    pub fn clear_penalty(&self, id: &str) {
+        self.penalties.remove(id);
+    }
+
+    pub fn is_penalized(&self, id: &str) -> bool {
+        self.penalties
+            .get(id)
+            .is_some_and(|p| p.until > Instant::now())
+    }
+
     pub fn add(&self, proxy: Proxy) {
         self.proxies.insert(proxy.id.clone(), proxy);
     }
@@ -82,3 +265,127 @@ impl RotationMode {
         }
     }
 }
+
+/// The protocol spoken by clients of the local listener.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InboundProtocol {
+    /// Detect SOCKS4/4a, SOCKS5 or HTTP from the first byte of each
+    /// connection, so one port serves every client.
+    #[default]
+    Auto,
+    /// SOCKS5 (RFC 1928), CONNECT only.
+    Socks5,
+    /// HTTP proxy speaking the `CONNECT` method.
+    Http,
+    /// Linux only: connections diverted by iptables/nftables `REDIRECT` or
+    /// `TPROXY`, relayed to their original destination without a handshake.
+    Transparent,
+}
+
+impl InboundProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InboundProtocol::Auto => "auto",
+            InboundProtocol::Socks5 => "socks5",
+            InboundProtocol::Http => "http",
+            InboundProtocol::Transparent => "transparent",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(InboundProtocol::Auto),
+            "socks5" => Some(InboundProtocol::Socks5),
+            "http" => Some(InboundProtocol::Http),
+            "transparent" => Some(InboundProtocol::Transparent),
+            _ => None,
+        }
+    }
+}
+
+/// What a listener does when none of its proxies is alive.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Fallback {
+    /// Fail the request as "no alive proxies".
+    #[default]
+    None,
+    /// Connect from this machine.
+    Direct,
+    /// Refuse the request as "not allowed".
+    Reject,
+}
+
+impl Fallback {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fallback::None => "none",
+            Fallback::Direct => "direct",
+            Fallback::Reject => "reject",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Fallback::None),
+            "direct" => Some(Fallback::Direct),
+            "reject" => Some(Fallback::Reject),
+            _ => None,
+        }
+    }
+
+    /// The built-in upstream to fall back to, if any.
+    pub fn upstream(&self) -> Option<Proxy> {
+        match self {
+            Fallback::None => None,
+            Fallback::Direct => Proxy::builtin(DIRECT_ID),
+            Fallback::Reject => Proxy::builtin(REJECT_ID),
+        }
+    }
+}
+
+/// One local listener: where it binds, what clients speak to it and how it
+/// picks upstreams.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    pub id: String,
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub protocol: InboundProtocol,
+    pub rotation_mode: RotationMode,
+    /// Upstream proxy ids this listener may use. Empty means the whole pool.
+    /// May name the built-in `direct` and `reject` upstreams.
+    #[serde(default)]
+    pub proxy_ids: Vec<String>,
+    #[serde(default)]
+    pub fallback: Fallback,
+    /// How many other upstreams to try when the selected one fails, before
+    /// the client gets an error.
+    #[serde(default = "default_failover_retries")]
+    pub failover_retries: u32,
+    /// How long the selected upstream gets to open its tunnel before a second
+    /// one is raced against it, in milliseconds. 0 disables hedging.
+    #[serde(default)]
+    pub hedge_delay_ms: u32,
+    /// A proxy group to draw upstreams from, with its own rotation, instead
+    /// of `proxy_ids` and `rotation_mode`.
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// Failover attempts for listeners that don't set their own.
+pub const DEFAULT_FAILOVER_RETRIES: u32 = 2;
+
+fn default_failover_retries() -> u32 {
+    DEFAULT_FAILOVER_RETRIES
+}
+
+/// A listener as reported to the frontend.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerInfo {
+    #[serde(flatten)]
+    pub config: ListenerConfig,
+    pub is_running: bool,
+}
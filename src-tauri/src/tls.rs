@@ -0,0 +1,112 @@
+use crate::engine::UpstreamStream;
+use crate::error::UpstreamError;
+use std::sync::{Arc, OnceLock};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+// TLS to the upstream proxy itself (`https://` proxies). The proxy protocol
+// then runs inside the session, so credentials never cross the wire in clear.
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn build_config(verify: bool) -> Arc<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions");
+    let config = if verify {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider())))
+            .with_no_client_auth()
+    };
+    Arc::new(config)
+}
+
+fn client_config(verify: bool) -> Arc<ClientConfig> {
+    static VERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static UNVERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if verify {
+        VERIFIED.get_or_init(|| build_config(true)).clone()
+    } else {
+        UNVERIFIED.get_or_init(|| build_config(false)).clone()
+    }
+}
+
+/// Accepts any certificate, for proxies with self-signed or mismatched
+/// certificates. Handshake signatures are still checked, so the session is
+/// encrypted, just not authenticated.
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Runs the TLS handshake with the proxy at `host`, sending it as SNI (IP
+/// literals are verified against the certificate's IP SANs instead).
+pub(crate) async fn connect(
+    stream: UpstreamStream,
+    host: &str,
+    verify: bool,
+) -> Result<UpstreamStream, UpstreamError> {
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|_| UpstreamError::Tls(format!("Invalid TLS server name {}", host)))?;
+    TlsConnector::from(client_config(verify))
+        .connect(server_name, stream)
+        .await
+        .map(|tls| Box::new(tls) as UpstreamStream)
+        .map_err(|e| UpstreamError::Tls(e.to_string()))
+}
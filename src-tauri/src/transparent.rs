@@ -0,0 +1,157 @@
+use crate::engine::{dial_upstream, select_upstream, ClientContext};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+// Transparent mode accepts connections diverted by the kernel (iptables /
+// nftables `REDIRECT` or `TPROXY`) and recovers where they were headed, so
+// clients never speak a proxy protocol at all. Linux only.
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::os::fd::AsRawFd;
+    use tokio::net::TcpStream;
+
+    /// `SO_ORIGINAL_DST` from `linux/netfilter_ipv4.h`; the IPv6 variant
+    /// (`IP6T_SO_ORIGINAL_DST`) shares the same value.
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+
+    /// Destination recorded by netfilter before a `REDIRECT`/`DNAT`.
+    pub fn original_dst(stream: &TcpStream) -> std::io::Result<SocketAddr> {
+        let fd = stream.as_raw_fd();
+        // SAFETY: the buffers are plain-old-data sockaddr structs sized by
+        // `len`, and `fd` is a live socket owned by `stream`.
+        unsafe {
+            if stream.local_addr()?.is_ipv4() {
+                let mut addr: libc::sockaddr_in = std::mem::zeroed();
+                let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+                if libc::getsockopt(
+                    fd,
+                    libc::SOL_IP,
+                    SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                ) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+            } else {
+                let mut addr: libc::sockaddr_in6 = std::mem::zeroed();
+                let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+                if libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                ) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin6_port)))
+            }
+        }
+    }
+
+    /// Marks a listening socket `IP_TRANSPARENT` (`IPV6_TRANSPARENT` for v6)
+    /// so `TPROXY` can hand it connections for foreign addresses. Requires
+    /// `CAP_NET_ADMIN`.
+    pub fn set_ip_transparent(socket: &tokio::net::TcpSocket, ipv6: bool) -> std::io::Result<()> {
+        let (level, name) = if ipv6 {
+            (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+        } else {
+            (libc::SOL_IP, libc::IP_TRANSPARENT)
+        };
+        let one: libc::c_int = 1;
+        // SAFETY: `one` outlives the call and the length matches its type.
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &one as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Binds the listener for transparent mode. `IP_TRANSPARENT` is best-effort:
+/// without it `REDIRECT` still works, only `TPROXY` does not.
+pub(crate) async fn bind_listener(addr: &str) -> std::io::Result<TcpListener> {
+    let addr: SocketAddr = addr.parse().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid listen address {}", addr),
+        )
+    })?;
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+
+    #[cfg(target_os = "linux")]
+    if let Err(e) = sys::set_ip_transparent(&socket, addr.is_ipv6()) {
+        eprintln!(
+            "[Transparent] IP_TRANSPARENT unavailable, TPROXY disabled: {}",
+            e
+        );
+    }
+
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Where the client was originally trying to go. `REDIRECT` leaves it in
+/// `SO_ORIGINAL_DST`; `TPROXY` leaves the socket bound to it directly.
+#[cfg(target_os = "linux")]
+fn original_destination(client: &TcpStream, listen_port: u16) -> std::io::Result<SocketAddr> {
+    let dst = match sys::original_dst(client) {
+        Ok(dst) => dst,
+        Err(_) => client.local_addr()?,
+    };
+    // Still our own address: the connection was not diverted, and relaying
+    // it would loop straight back here.
+    if dst.port() == listen_port && client.local_addr()? == dst {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Connection was not redirected to the transparent listener",
+        ));
+    }
+    Ok(dst)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_destination(_client: &TcpStream, _listen_port: u16) -> std::io::Result<SocketAddr> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Transparent proxying is only supported on Linux",
+    ))
+}
+
+// ─── Transparent handler ────────────────────────────────────────────────────
+
+pub(crate) async fn handle_transparent(
+    client: &mut TcpStream,
+    ctx: ClientContext,
+    listen_port: u16,
+) -> std::io::Result<()> {
+    let dst = original_destination(client, listen_port)?;
+    let target_addr = dst.ip().to_string();
+
+    let selected = select_upstream(&ctx, &target_addr, dst.port())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No alive proxies"))?;
+
+    let mut tunnel = dial_upstream(&ctx, selected, &target_addr, dst.port()).await?;
+    tokio::io::copy_bidirectional(client, &mut tunnel.stream).await?;
+    Ok(())
+}
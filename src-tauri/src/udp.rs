@@ -0,0 +1,279 @@
+use crate::engine::{dial_with, select_upstream_where, ClientContext};
+use crate::error::UpstreamError;
+use crate::socks;
+use crate::state::Proxy;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Largest datagram we relay (the maximum UDP payload).
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Errors a UDP socket reports for a single datagram, typically an ICMP
+/// unreachable from an earlier send. They leave the socket usable.
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        ConnectionRefused
+            | ConnectionReset
+            | HostUnreachable
+            | NetworkUnreachable
+            | Interrupted
+            | WouldBlock
+    )
+}
+
+/// An open UDP ASSOCIATE on an upstream SOCKS5 proxy. The association lives
+/// exactly as long as its TCP control connection.
+struct UpstreamAssociation {
+    control: TcpStream,
+    relay: SocketAddr,
+}
+
+async fn associate_upstream(selected: &Proxy) -> Result<UpstreamAssociation, UpstreamError> {
+    let proxy_addr = format!("{}:{}", selected.host, selected.port);
+    let mut control = TcpStream::connect(&proxy_addr)
+        .await
+        .map_err(UpstreamError::ProxyUnreachable)?;
+    socks::handshake(
+        &mut control,
+        selected.user.as_deref(),
+        selected.pass.as_deref(),
+    )
+    .await?;
+
+    // We don't know our outbound UDP address yet, so announce 0.0.0.0:0.
+    let (relay_host, relay_port) =
+        socks::request(&mut control, socks::CMD_UDP_ASSOCIATE, "0.0.0.0", 0).await?;
+
+    let relay_host = socks::resolve_unspecified(relay_host, control.peer_addr()?.ip());
+    let relay_ip = match relay_host.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => tokio::net::lookup_host((relay_host.as_str(), relay_port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Cannot resolve UDP relay {}", relay_host),
+                )
+            })?
+            .ip(),
+    };
+
+    Ok(UpstreamAssociation {
+        control,
+        relay: SocketAddr::new(relay_ip, relay_port),
+    })
+}
+
+// ─── UDP ASSOCIATE handler ──────────────────────────────────────────────────
+
+/// Serves a SOCKS5 UDP ASSOCIATE request. The first UDP-capable upstream to
+/// accept the association is used for all of it; client datagrams already
+/// carry the SOCKS5 UDP header, so they are relayed to the upstream relay
+/// unchanged and vice versa.
+pub(crate) async fn handle_udp_associate(
+    client: &mut TcpStream,
+    ctx: ClientContext,
+    target_host: &str,
+    target_port: u16,
+) -> std::io::Result<()> {
+    let selected = match select_upstream_where(&ctx, target_host, target_port, Proxy::supports_udp)
+    {
+        Some(p) => p,
+        None => {
+            client
+                .write_all(&socks::reply(socks::REPLY_COMMAND_NOT_SUPPORTED, None))
+                .await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No alive UDP-capable proxies",
+            ));
+        }
+    };
+
+    let associated = dial_with(
+        &ctx,
+        selected,
+        target_host,
+        target_port,
+        false,
+        Proxy::supports_udp,
+        |p| async move { associate_upstream(&p).await.map(|a| (p, a)) },
+    );
+    let (selected, mut upstream) = match associated.await {
+        Ok(a) => a,
+        Err(e) => {
+            client
+                .write_all(&socks::reply(e.reply_code(), None))
+                .await?;
+            return Err(e.into());
+        }
+    };
+
+    let local = UdpSocket::bind(SocketAddr::new(client.local_addr()?.ip(), 0)).await?;
+    let outbound = UdpSocket::bind(if upstream.relay.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+
+    println!(
+        "[Proxy] UDP association via {} ({}:{}) relay {}",
+        selected.id, selected.host, selected.port, upstream.relay
+    );
+
+    client
+        .write_all(&socks::reply(
+            socks::REPLY_SUCCEEDED,
+            Some(local.local_addr()?),
+        ))
+        .await?;
+
+    let client_ip = client.peer_addr()?.ip();
+    let mut client_udp: Option<SocketAddr> = None;
+    let mut from_client = vec![0u8; MAX_DATAGRAM];
+    let mut from_relay = vec![0u8; MAX_DATAGRAM];
+    let mut tcp_buf = [0u8; 64];
+    let mut control_buf = [0u8; 64];
+
+    loop {
+        tokio::select! {
+            // Either control connection closing ends the association.
+            r = client.read(&mut tcp_buf) => {
+                if matches!(r, Ok(0) | Err(_)) {
+                    break;
+                }
+            }
+            r = upstream.control.read(&mut control_buf) => {
+                if matches!(r, Ok(0) | Err(_)) {
+                    break;
+                }
+            }
+            r = local.recv_from(&mut from_client) => {
+                let (n, from) = match r {
+                    Ok(v) => v,
+                    Err(e) if is_transient(&e) => {
+                        eprintln!("[UDP] Client socket error, continuing: {}", e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                // Only accept datagrams from the client that opened the
+                // association, and drop fragments (FRAG != 0).
+                if from.ip() != client_ip || n < 4 || from_client[2] != 0 {
+                    continue;
+                }
+                client_udp = Some(from);
+                if let Err(e) = outbound.send_to(&from_client[..n], upstream.relay).await {
+                    if !is_transient(&e) {
+                        return Err(e);
+                    }
+                    eprintln!("[UDP] Dropped datagram to relay {}: {}", upstream.relay, e);
+                }
+            }
+            r = outbound.recv_from(&mut from_relay) => {
+                let (n, from) = match r {
+                    Ok(v) => v,
+                    Err(e) if is_transient(&e) => {
+                        eprintln!("[UDP] Relay socket error, continuing: {}", e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if from.ip() != upstream.relay.ip() {
+                    continue;
+                }
+                if let Some(addr) = client_udp {
+                    if let Err(e) = local.send_to(&from_relay[..n], addr).await {
+                        if !is_transient(&e) {
+                            return Err(e);
+                        }
+                        eprintln!("[UDP] Dropped datagram to client {}: {}", addr, e);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    /// Binds a port and frees it again, so connecting to it is refused.
+    async fn closed_port() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn associates_through_the_next_proxy_when_one_is_down() {
+        // A SOCKS5 proxy that grants the association with a relay on 7777.
+        let live = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_port = live.local_addr().unwrap().port();
+        let upstream = tokio::spawn(async move {
+            let (mut st, _) = live.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            st.read_exact(&mut greeting).await.unwrap();
+            st.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = [0u8; 10];
+            st.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[1], socks::CMD_UDP_ASSOCIATE);
+            st.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1e, 0x61])
+                .await
+                .unwrap();
+            // Hold the control connection until the client goes away.
+            let _ = st.read(&mut [0u8; 1]).await;
+        });
+
+        let pool = crate::state::ProxyPool::new();
+        for (id, protocol, port) in [
+            ("a-dead", "socks5", closed_port().await),
+            ("b-http", "http", live_port),
+            ("c-live", "socks5", live_port),
+        ] {
+            pool.add(Proxy {
+                id: id.to_string(),
+                protocol: protocol.to_string(),
+                host: "127.0.0.1".to_string(),
+                port,
+                is_alive: true,
+                ..Default::default()
+            });
+        }
+        let ctx = ClientContext::for_pool(pool.clone());
+
+        let inbound = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(inbound.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut accepted, _) = inbound.accept().await.unwrap();
+        let handler =
+            tokio::spawn(
+                async move { handle_udp_associate(&mut accepted, ctx, "0.0.0.0", 0).await },
+            );
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], socks::REPLY_SUCCEEDED);
+        assert!(pool.is_penalized("a-dead"));
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+        upstream.await.unwrap();
+    }
+
+    #[test]
+    fn icmp_errors_are_transient() {
+        assert!(is_transient(&Error::from(ErrorKind::ConnectionRefused)));
+        assert!(is_transient(&Error::from(ErrorKind::HostUnreachable)));
+        assert!(!is_transient(&Error::from(ErrorKind::PermissionDenied)));
+        assert!(!is_transient(&Error::from(ErrorKind::InvalidInput)));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::engine::{connect_proxy, join_host_port, prepare, UpstreamStream};
+use crate::error::UpstreamError;
+use crate::state::{Proxy, ProxyPool};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Warm pool: idle connections to HTTP(S) and SOCKS5 upstreams, opened ahead
+// of time so a client connection only waits for its own request. HTTP(S)
+// connections are kept after the TCP (and TLS) setup, SOCKS5 ones after the
+// greeting and authentication. Health checks never use them, so measured
+// latency still includes the setup.
+
+/// How often idle connections are expired and topped up, besides right after
+/// one is taken.
+const REFILL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long one warm-up dial may take.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Idle plus in-flight connections across all proxies. With a large pool the
+/// fastest proxies are warmed first and the rest go without.
+pub(crate) const MAX_WARM_CONNECTIONS: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WarmPoolSettings {
+    /// Idle connections kept per proxy; 0 disables the pool.
+    pub size: u16,
+    /// Idle connections older than this are closed rather than handed out,
+    /// before the proxy gives up on them.
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for WarmPoolSettings {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            idle_timeout_secs: 30,
+        }
+    }
+}
+
+struct Idle {
+    stream: UpstreamStream,
+    since: Instant,
+}
+
+/// One proxy's idle connections plus the dials still under way for it.
+#[derive(Default)]
+struct Slot {
+    idle: Vec<Idle>,
+    pending: usize,
+}
+
+fn slot_key(p: &Proxy) -> String {
+    format!(
+        "{}://{}:{}@{}",
+        p.protocol,
+        p.user.as_deref().unwrap_or(""),
+        p.pass.as_deref().unwrap_or(""),
+        join_host_port(&p.host, p.port)
+    )
+}
+
+/// Whether connections to `p` can be opened before the target is known.
+fn is_warmable(p: &Proxy) -> bool {
+    p.is_http() || p.is_socks5()
+}
+
+#[derive(Clone, Default)]
+pub struct WarmPool {
+    settings: Arc<Mutex<WarmPoolSettings>>,
+    /// Slots by proxy endpoint, so an edited proxy doesn't get connections
+    /// opened with its old address or credentials.
+    slots: Arc<Mutex<HashMap<String, Slot>>>,
+    /// Wakes the refiller early, after a connection was taken or the
+    /// settings changed.
+    wakeup: Arc<Notify>,
+}
+
+impl WarmPool {
+    pub fn get_settings(&self) -> WarmPoolSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn set_settings(&self, new: WarmPoolSettings) {
+        *self.settings.lock().unwrap() = new;
+        self.wakeup.notify_one();
+    }
+
+    /// An idle connection to `p`, ready for its request, if one is waiting.
+    pub(crate) fn take(&self, p: &Proxy) -> Option<UpstreamStream> {
+        if !is_warmable(p) {
+            return None;
+        }
+        let idle_timeout = Duration::from_secs(self.get_settings().idle_timeout_secs);
+        let stream = {
+            let mut slots = self.slots.lock().unwrap();
+            let slot = slots.get_mut(&slot_key(p))?;
+            slot.idle.retain(|c| c.since.elapsed() < idle_timeout);
+            // The newest connection is the least likely to have been dropped.
+            slot.idle.pop().map(|c| c.stream)
+        };
+        if stream.is_some() {
+            self.wakeup.notify_one();
+        }
+        stream
+    }
+
+    /// Expires idle connections, drops those of proxies that are gone, dead
+    /// or penalized, and starts dials for slots below their size, fastest
+    /// proxies first, until `MAX_WARM_CONNECTIONS` is reached.
+    fn refill(&self, pool: &ProxyPool) {
+        let WarmPoolSettings {
+            size,
+            idle_timeout_secs,
+        } = self.get_settings();
+        let idle_timeout = Duration::from_secs(idle_timeout_secs);
+        let wanted: HashMap<String, Proxy> = if size == 0 {
+            HashMap::new()
+        } else {
+            pool.get_all()
+                .into_iter()
+                .filter(|p| p.is_alive && is_warmable(p) && !pool.is_penalized(&p.id))
+                .map(|p| (slot_key(&p), p))
+                .collect()
+        };
+
+        let mut dials = Vec::new();
+        {
+            let mut slots = self.slots.lock().unwrap();
+            slots.retain(|key, slot| {
+                if wanted.contains_key(key) {
+                    slot.idle.retain(|c| c.since.elapsed() < idle_timeout);
+                } else {
+                    slot.idle.clear();
+                }
+                // Keep the slot while a dial will still report back to it.
+                wanted.contains_key(key) || slot.pending > 0
+            });
+            let mut budget = MAX_WARM_CONNECTIONS
+                .saturating_sub(slots.values().map(|s| s.idle.len() + s.pending).sum());
+            let mut wanted: Vec<_> = wanted.into_iter().collect();
+            wanted.sort_by_key(|(_, p)| p.latency_ms.unwrap_or(u64::MAX));
+            for (key, p) in wanted {
+                let slot = slots.entry(key.clone()).or_default();
+                let missing = (size as usize)
+                    .saturating_sub(slot.idle.len() + slot.pending)
+                    .min(budget);
+                budget -= missing;
+                slot.pending += missing;
+                dials.extend(std::iter::repeat_n((key, p), missing));
+            }
+        }
+        for (key, p) in dials {
+            tauri::async_runtime::spawn(self.clone().dial(key, p));
+        }
+    }
+
+    async fn dial(self, key: String, p: Proxy) {
+        let result = tokio::time::timeout(DIAL_TIMEOUT, async {
+            let mut st = connect_proxy(&p).await?;
+            prepare(&mut st, &p).await?;
+            Ok::<_, UpstreamError>(st)
+        })
+        .await;
+
+        let mut slots = self.slots.lock().unwrap();
+        let Some(slot) = slots.get_mut(&key) else {
+            return;
+        };
+        slot.pending -= 1;
+        match result {
+            Ok(Ok(stream)) => slot.idle.push(Idle {
+                stream,
+                since: Instant::now(),
+            }),
+            Ok(Err(e)) => eprintln!("[Warm] Cannot pre-connect to {}: {}", p.id, e),
+            Err(_) => eprintln!("[Warm] Pre-connecting to {} timed out", p.id),
+        }
+    }
+}
+
+pub fn start_warmer(warm: WarmPool, pool: ProxyPool) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            warm.refill(&pool);
+            let _ = tokio::time::timeout(REFILL_INTERVAL, warm.wakeup.notified()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refill_stays_under_the_global_cap() {
+        let pool = ProxyPool::new();
+        for i in 0..300u16 {
+            pool.add(Proxy {
+                id: format!("p{}", i),
+                protocol: "socks5".to_string(),
+                host: "127.0.0.1".to_string(),
+                port: 1 + i,
+                is_alive: true,
+                latency_ms: Some(i.into()),
+                ..Default::default()
+            });
+        }
+        let warm = WarmPool::default();
+        warm.set_settings(WarmPoolSettings {
+            size: 2,
+            idle_timeout_secs: 30,
+        });
+        warm.refill(&pool);
+
+        let slots = warm.slots.lock().unwrap();
+        let pending: usize = slots.values().map(|s| s.pending).sum();
+        assert_eq!(pending, MAX_WARM_CONNECTIONS);
+        // The fastest proxies were served first.
+        let fastest = pool.get_all().into_iter().find(|p| p.id == "p0").unwrap();
+        assert_eq!(slots[&slot_key(&fastest)].pending, 2);
+        let slowest = pool.get_all().into_iter().find(|p| p.id == "p299").unwrap();
+        assert_eq!(slots[&slot_key(&slowest)].pending, 0);
+    }
+}
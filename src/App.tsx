@@ -10,6 +10,20 @@ import { Plus, Power, Layers, Settings, Activity, Trash2, Sparkles } from "lucid
 
 type Page = "proxies" | "settings";
 
+// `user@host:port[:pass]` (as in `ssh://`) carries the username before the host.
+function splitUserHost(parts: string[]) {
+  let host = parts[0];
+  let user = parts[2] || null;
+  let pass = parts[3] || null;
+  const at = host.lastIndexOf('@');
+  if (at !== -1) {
+    user = host.slice(0, at);
+    host = host.slice(at + 1);
+    pass = parts[2] || null;
+  }
+  return { host, user, pass };
+}
+
 function App() {
   const [proxies, setProxies] = useState<Proxy[]>([]);
   const [isServerActive, setIsServerActive] = useState(false);
@@ -104,10 +118,8 @@ function App() {
 
     const parts = currentLine.split(':');
     if (parts.length >= 2) {
-      const host = parts[0];
+      const { host, user, pass } = splitUserHost(parts);
       const port = parseInt(parts[1], 10);
-      const user = parts[2] || null;
-      const pass = parts[3] || null;
       try {
         await invoke("update_proxy", { id, protocol, host, port, user, pass });
         await fetchProxies();
@@ -137,10 +149,8 @@ function App() {
 
       const parts = currentLine.split(':');
       if (parts.length >= 2) {
-        const host = parts[0];
+        const { host, user, pass } = splitUserHost(parts);
         const port = parseInt(parts[1], 10);
-        const user = parts[2] || null;
-        const pass = parts[3] || null;
         try {
           const id = await invoke<string>("add_proxy", { protocol, host, port, user, pass });
           newProxyIds.push(id);
@@ -164,6 +174,7 @@ function App() {
       await fetchProxies();
     } catch (e) {
       console.error("Failed to remove proxy:", e);
+      window.alert(`Cannot remove proxy: ${e}`);
     }
     setProxyToDelete(null);
   };
@@ -176,6 +187,7 @@ function App() {
         await fetchProxies();
       } catch (e) {
         console.error("Failed to clear proxies:", e);
+        window.alert(`Cannot clear proxies: ${e}`);
       }
     }
   };
@@ -185,8 +197,11 @@ function App() {
     if (deadCount === 0) return;
     if (window.confirm(`Are you sure you want to delete ${deadCount} offline proxies?`)) {
       try {
-        await invoke("clear_dead_proxies");
+        const kept = await invoke<number>("clear_dead_proxies");
         await fetchProxies();
+        if (kept > 0) {
+          window.alert(`${kept} offline proxies were kept because chains, rules or listeners use them.`);
+        }
       } catch (e) {
         console.error("Failed to clear dead proxies:", e);
       }
@@ -70,8 +70,11 @@ export function AddProxyModal({ onClose, onAdd }: AddProxyModalProps) {
                                 >
                                     <option value="socks5">SOCKS5</option>
                                     <option value="socks4">SOCKS4</option>
+                                    <option value="socks4a">SOCKS4a</option>
                                     <option value="http">HTTP</option>
                                     <option value="https">HTTPS</option>
+                                    <option value="ss">Shadowsocks</option>
+                                    <option value="ssh">SSH</option>
                                 </select>
                             </div>
                         </div>
@@ -1,6 +1,6 @@
 import { useRef, useState, useEffect } from "react";
 import { Proxy } from "../types";
-import { Activity, ServerOff, Shield, ShieldOff, Edit } from "lucide-react";
+import { Activity, ServerOff, Shield, ShieldOff, Edit, Globe } from "lucide-react";
 
 interface ProxyListProps {
     proxies: Proxy[];
@@ -124,6 +124,15 @@ export function ProxyList({ proxies, onLongPress, onEdit, onCheckHealth, onDelet
                                         <ShieldOff className="w-3 h-3" /> No auth
                                     </span>
                                 )}
+                                {p.geo?.country && (
+                                    <span
+                                        className="flex items-center gap-1 text-gray-500"
+                                        title={[p.geo.city, p.geo.as_org, p.exit_ip].filter(Boolean).join(' · ')}
+                                    >
+                                        <Globe className="w-3 h-3" /> {p.geo.country}
+                                        {p.geo.asn != null && <span className="text-gray-700">AS{p.geo.asn}</span>}
+                                    </span>
+                                )}
                             </div>
                         </div>
                     </div>
@@ -7,4 +7,110 @@ export interface Proxy {
     pass?: string;
     latency_ms?: number;
     is_alive: boolean;
+    skip_tls_verify?: boolean;
+    cipher?: string;
+    /** `ssh` only. `update_proxy` keeps it when omitted; send `""` to clear it. */
+    private_key?: string;
+    /** `ssh` only: pinned host key. `update_proxy` keeps it when omitted; send `""` to clear it. */
+    host_key?: string;
+    /** `chain` only: pool ids of the hops, entry first. */
+    hops?: string[];
+    /** `http`/`https` only: extra CONNECT headers, with placeholders. */
+    headers?: Record<string, string>;
+    /** Labels routing rules can select proxies by. */
+    tags?: string[];
+    /** Address the proxy's traffic leaves from, once a health check learned it. */
+    exit_ip?: string | null;
+    /** What a hostname `host` last resolved to, used until `exit_ip` is known. */
+    host_ip?: string | null;
+    geo?: GeoInfo | null;
+}
+
+export interface GeoInfo {
+    /** ISO 3166-1 alpha-2 code, e.g. `DE`. */
+    country: string | null;
+    city: string | null;
+    asn: number | null;
+    as_org: string | null;
+}
+
+export interface GeoIpDatabase {
+    path: string;
+    database_type: string | null;
+    build_epoch: number | null;
+    /** Why the file could not be opened, if it couldn't. */
+    error: string | null;
+}
+
+export interface ListenerInfo {
+    id: string;
+    name: string;
+    host: string;
+    port: number;
+    protocol: string;
+    rotation_mode: string;
+    proxy_ids: string[];
+    fallback: string;
+    failover_retries: number;
+    hedge_delay_ms: number;
+    /** Proxy group used instead of `proxy_ids`, if any. */
+    group: string | null;
+    is_running: boolean;
+}
+
+export interface ProxyGroup {
+    id: string;
+    name: string;
+    /** A proxy is a member when it carries all of these. */
+    tags: string[];
+    rotation_mode: string;
+    /** Ids of the proxies currently in the group. */
+    members: string[];
+}
+
+export interface PortMapSettings {
+    enabled: boolean;
+    /** Always "127.0.0.1": mapped ports take no inbound auth. */
+    host: string;
+    base_port: number;
+    size: number;
+}
+
+export interface WarmPoolSettings {
+    size: number;
+    idle_timeout_secs: number;
+}
+
+export interface PortMapping {
+    port: number;
+    proxy_id: string;
+    protocol: string;
+    host: string;
+    proxy_port: number;
+    is_alive: boolean;
+    is_listening: boolean;
+}
+
+export type RuleCondition =
+    | { type: "domain"; value: string }
+    | { type: "domain_suffix"; value: string }
+    | { type: "domain_keyword"; value: string }
+    | { type: "domain_regex"; value: string }
+    | { type: "ip_cidr"; value: string }
+    | { type: "port"; value: string }
+    | { type: "listener"; value: string };
+
+export type RuleAction =
+    | { type: "proxy"; value: string }
+    | { type: "tag"; value: string }
+    | { type: "group"; value: string }
+    | { type: "direct" }
+    | { type: "reject" };
+
+export interface Rule {
+    id: string;
+    name: string;
+    enabled: boolean;
+    conditions: RuleCondition[];
+    action: RuleAction;
 }